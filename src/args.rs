@@ -1,23 +1,128 @@
-use std::path::PathBuf;
+use std::{
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
-use crate::Id;
+use crate::{
+    dedup::Dedup,
+    embed::{parse_custom_embed, parse_provider_args, BuiltinEmbed, CustomEmbed},
+    metadata::SourceMetadata,
+    queue::Schedule,
+    sanitize::{Normalization, SanitizeProfile},
+    throttle::Provider,
+    Id,
+};
 
 #[derive(Parser)]
-#[command(author, version, about, long_about = None)]
+#[command(
+    author,
+    version,
+    about,
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 pub(crate) struct Args {
+    #[command(subcommand)]
+    pub command: Option<Commands>,
+
+    /// Download the course (default command)
+    #[command(flatten)]
+    pub download: DownloadArgs,
+
+    #[command(flatten)]
+    pub verbosity: clap_verbosity_flag::Verbosity,
+}
+
+#[derive(Subcommand)]
+pub(crate) enum Commands {
+    /// Periodically re-sync the course, downloading only new or changed content
+    Watch(Box<WatchArgs>),
+
+    /// List offline-cached courses, with the days remaining until their license expires
+    Status(CacheArgs),
+
+    /// Delete offline-cached courses whose license has expired
+    Purge(PurgeArgs),
+
+    /// Re-hash offline-cached courses, and report missing, truncated or modified files
+    Verify(CacheArgs),
+
+    /// Download missing, corrupt or incomplete files of the course again
+    Repair(Box<RepairArgs>),
+}
+
+#[derive(clap::Args)]
+pub(crate) struct CacheArgs {
+    /// Target-dir
+    #[arg(short, long, env = "ELOPAGE_DIR")]
+    pub output_dir: PathBuf,
+}
+
+#[derive(clap::Args)]
+pub(crate) struct PurgeArgs {
+    #[command(flatten)]
+    pub cache: CacheArgs,
+
+    /// Only list the courses which would be deleted
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Delete without asking for confirmation
+    #[arg(short, long)]
+    pub yes: bool,
+}
+
+#[derive(clap::Args)]
+pub(crate) struct WatchArgs {
+    #[command(flatten)]
+    pub download: DownloadArgs,
+
+    /// Time between syncs, such as "6h" or "1day 12h"
+    #[arg(long, env = "WATCH_INTERVAL", default_value = "6h", value_parser = humantime::parse_duration)]
+    pub interval: Duration,
+
+    /// Upper bound of a random delay added to each interval, to avoid syncing at predictable times
+    #[arg(long, env = "WATCH_JITTER", default_value = "15m", value_parser = humantime::parse_duration)]
+    pub jitter: Duration,
+
+    /// Command to run through the shell whenever new or changed content arrived
+    ///
+    /// The course directory and the path to the JSON change report are passed
+    /// in the `ELOPAGE_COURSE_DIR` and `ELOPAGE_CHANGES_FILE` environment variables.
+    #[arg(long, env = "WATCH_ON_CHANGE")]
+    pub on_change: Option<String>,
+}
+
+#[derive(clap::Args)]
+pub(crate) struct RepairArgs {
+    #[command(flatten)]
+    pub download: DownloadArgs,
+
+    /// Only list the files which would be downloaded again
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+/// Options for downloading a course.
+///
+/// `--course-id`, `--token` and `--output-dir` are declared optional, so that subcommands can be used
+/// without passing them to the top-level command. Clap enforces their presence wherever they are required.
+#[derive(clap::Args, Clone, Debug)]
+pub(crate) struct DownloadArgs {
     /// The Course ID
-    #[arg(short, long, env = "COURSE_ID")]
-    pub course_id: Id,
+    #[arg(short, long, env = "COURSE_ID", required = true)]
+    pub course_id: Option<Id>,
 
     /// The authorization token
-    #[arg(short, long, env = "AUTH_TOKEN")]
-    pub token: String,
+    #[arg(short, long, env = "AUTH_TOKEN", required = true)]
+    pub token: Option<String>,
 
     /// Target-dir
-    #[arg(short, long, env = "ELOPAGE_DIR")]
-    pub output_dir: String,
+    #[arg(short, long, env = "ELOPAGE_DIR", required = true)]
+    pub output_dir: Option<String>,
 
     /// User agent (browser signature)
     #[arg(
@@ -36,10 +141,200 @@ pub(crate) struct Args {
     #[arg(short, long, env = "PARALLEL_DOWNLOADS", default_value_t = 1)]
     pub parallel: usize,
 
-    /// Path to the `yt-dlp` binary - required only if vimeo iframes are used.
+    /// Download up to N files at the same time, across all lessons [default: same as `--parallel`]
+    #[arg(long, env = "PARALLEL_ASSETS")]
+    pub parallel_assets: Option<usize>,
+
+    /// Order in which files are downloaded
+    #[arg(long, env = "SCHEDULE", value_enum, default_value_t = Schedule::CourseOrder)]
+    pub schedule: Schedule,
+
+    /// Download large files over N connections at the same time, if the server supports it
+    #[arg(long, env = "CONNECTIONS", default_value_t = 1)]
+    pub connections: usize,
+
+    /// Minimum size of files downloaded over multiple connections, such as "64MB"
+    #[arg(long, env = "CHUNKED_MIN_SIZE", default_value = "64MB", value_parser = crate::space::parse_size)]
+    pub chunked_min_size: u64,
+
+    /// Limit the concurrent downloads from a provider, such as "vimeo=2"
+    ///
+    /// Providers: elopage, wistia, vimeo, youtube, other. May be passed multiple times.
+    #[arg(long = "max-per-provider", env = "MAX_PER_PROVIDER", value_delimiter = ',', value_parser = crate::throttle::parse_provider_limit)]
+    pub provider_limits: Vec<(Provider, usize)>,
+
+    /// Halve a provider's concurrency when it throttles downloads (HTTP 429 or 503), and slowly raise it back up
+    ///
+    /// Throttled downloads are retried.
+    #[arg(long, env = "ADAPTIVE_THROTTLING")]
+    pub adaptive_throttling: bool,
+
+    /// Limit the total download rate in bytes per second, such as "5M" or "500K"
+    ///
+    /// Each `yt-dlp` process is limited to one of `--parallel-assets` equal shares of the rate, which other downloads leave to it.
+    #[arg(long, env = "LIMIT_RATE", value_parser = crate::space::parse_size)]
+    pub limit_rate: Option<u64>,
+
+    /// Control file to adjust the rate limit while downloading
+    ///
+    /// Write a new rate such as "2M", or "0" for no limit, into the file. If it is removed, `--limit-rate` applies again.
+    #[arg(long, env = "LIMIT_RATE_FILE")]
+    pub limit_rate_file: Option<PathBuf>,
+
+    /// Path to the `yt-dlp` binary - required only if lesson texts embed videos.
     #[arg(short, long, env = "YT_DLP_BIN", default_value = "yt-dlp")]
     pub yt_dlp_bin: PathBuf,
 
-    #[command(flatten)]
-    pub verbosity: clap_verbosity_flag::Verbosity,
+    /// Providers of videos embedded into lesson texts to download
+    #[arg(
+        long,
+        env = "EMBED_PROVIDERS",
+        value_enum,
+        value_delimiter = ',',
+        default_value = "vimeo,youtube,wistia,loom,vidyard,bunny,jwplayer,soundcloud,video"
+    )]
+    pub embed_providers: Vec<BuiltinEmbed>,
+
+    /// Extra `yt-dlp` arguments for an embed provider, such as "vimeo=--format bestvideo*+bestaudio". Can be repeated
+    #[arg(long = "embed-args", value_name = "PROVIDER=ARGS", value_parser = parse_provider_args)]
+    pub embed_args: Vec<(String, Vec<String>)>,
+
+    /// Custom embed provider, downloading matching URLs with `yt-dlp`, such as "panopto=^https://[^/]+\.panopto\.eu/". Can be repeated
+    #[arg(long = "custom-embed", value_name = "NAME=REGEX", value_parser = parse_custom_embed)]
+    pub custom_embeds: Vec<CustomEmbed>,
+
+    /// Course directory template, relative to the target-dir
+    ///
+    /// Variables: {seller}, {seller_name}, {course}, {course_id}
+    #[arg(
+        long,
+        env = "COURSE_TEMPLATE",
+        default_value = "Elopage/{seller} ({seller_name})/{course}"
+    )]
+    pub course_template: String,
+
+    /// Category directory name template
+    ///
+    /// Variables: {name}, {index}, {position}, {id}, {depth}, {parent}, {seller}, {seller_name}, {course}, {course_id}
+    ///
+    /// `{index}` is zero-padded to fit the number of siblings. Use `{variable:0N}` to zero-pad any number to N digits.
+    #[arg(long, env = "CATEGORY_TEMPLATE", default_value = "{index} {name}")]
+    pub category_template: String,
+
+    /// Lesson directory name template
+    ///
+    /// Variables: see `--category-template`
+    #[arg(long, env = "LESSON_TEMPLATE", default_value = "{index} {name}")]
+    pub lesson_template: String,
+
+    /// Asset file name template
+    ///
+    /// Variables: {name}, {stem}, {ext}, {index}, {lesson}, {lesson_id}, {parent}, {seller}, {seller_name}, {course}, {course_id}
+    ///
+    /// `{index}` numbers the lesson's files and embedded videos in order of appearance.
+    #[arg(long, env = "ASSET_TEMPLATE", default_value = "{name}")]
+    pub asset_template: String,
+
+    /// Prefix file names with their order of appearance in the lesson, as with `--asset-template '{index} {name}'`
+    #[arg(long, env = "NUMBER_ASSETS")]
+    pub number_assets: bool,
+
+    /// Download images shown in lesson texts, from any host
+    #[arg(long, env = "IMAGES")]
+    pub images: bool,
+
+    /// Download files linked from lesson texts, on the hosts of `--link-hosts`
+    #[arg(long, env = "LINKS")]
+    pub links: bool,
+
+    /// Extensions of files linked from lesson texts to download.
+    /// Links without one of these extensions are downloaded if the server declares a matching type
+    #[arg(
+        long,
+        env = "LINK_EXTENSIONS",
+        value_delimiter = ',',
+        default_value = "pdf,epub,zip,doc,docx,xls,xlsx,ppt,pptx,odt,ods,odp,rtf,txt,csv,mp3,m4a,wav,mp4,mov"
+    )]
+    pub link_extensions: Vec<String>,
+
+    /// Hosts of files linked from lesson texts to download, including their subdomains. `*` allows any host
+    #[arg(
+        long,
+        env = "LINK_HOSTS",
+        value_delimiter = ',',
+        default_value = "elopage.com,amazonaws.com,cloudfront.net"
+    )]
+    pub link_hosts: Vec<String>,
+
+    /// File name rules of the file system the course is stored on or copied to
+    #[arg(long, env = "SANITIZE", value_enum, default_value_t = SanitizeProfile::Exfat)]
+    pub sanitize: SanitizeProfile,
+
+    /// Unicode normalization form of file names
+    #[arg(long, env = "UNICODE_NORMALIZATION", value_enum, default_value_t = Normalization::Nfc)]
+    pub unicode_normalization: Normalization,
+
+    /// Store the source URL, lesson ID and course ID of each downloaded file
+    #[arg(long, env = "SOURCE_METADATA", value_enum)]
+    pub source_metadata: Option<SourceMetadata>,
+
+    /// Store files which were downloaded before, to another lesson or course in the output directory,
+    /// as links to them instead of downloading them again. Falls back to copying where links are not supported
+    #[arg(long, env = "DEDUP", value_enum)]
+    pub dedup: Option<Dedup>,
+
+    /// Keep an existing offline cache in sync: move renamed or reordered lessons,
+    /// and skip files which have already been downloaded
+    #[arg(long, env = "MIRROR")]
+    pub mirror: bool,
+
+    /// With `--mirror`, delete local lessons and categories which were removed from the course
+    #[arg(
+        long,
+        env = "MIRROR_DELETE",
+        requires = "mirror",
+        conflicts_with = "archive"
+    )]
+    pub delete: bool,
+
+    /// With `--mirror`, move local lessons and categories which were removed from the course into a "Removed" folder
+    #[arg(long, env = "MIRROR_ARCHIVE", requires = "mirror")]
+    pub archive: bool,
+
+    /// Date your license expires, such as "2024-12-31", if not provided by elopage
+    #[arg(long, env = "LICENSE_EXPIRES", value_parser = crate::license::parse_date)]
+    pub license_expires: Option<SystemTime>,
+
+    /// Storage budget for the course, such as "20GB" or "500MiB"
+    ///
+    /// Lower Wistia video renditions are downloaded until the course fits the budget.
+    #[arg(long, env = "MAX_TOTAL_SIZE", value_parser = crate::space::parse_size)]
+    pub max_total_size: Option<u64>,
+
+    /// Download even if the course does not fit into the free space of the target-dir
+    #[arg(long, env = "IGNORE_FREE_SPACE")]
+    pub ignore_free_space: bool,
+}
+
+impl DownloadArgs {
+    /// Maximum number of files downloaded at the same time.
+    pub(crate) fn parallel_assets(&self) -> usize {
+        self.parallel_assets.unwrap_or(self.parallel).max(1)
+    }
+
+    pub(crate) fn course_id(&self) -> Id {
+        self.course_id.expect("`--course-id` is required by clap")
+    }
+
+    pub(crate) fn token(&self) -> &str {
+        self.token
+            .as_deref()
+            .expect("`--token` is required by clap")
+    }
+
+    pub(crate) fn output_dir(&self) -> &str {
+        self.output_dir
+            .as_deref()
+            .expect("`--output-dir` is required by clap")
+    }
 }
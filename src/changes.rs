@@ -0,0 +1,340 @@
+use std::{collections::BTreeMap, path::Path};
+
+use color_eyre::{eyre::WrapErr, Result};
+use serde::Serialize;
+use tokio::fs;
+use tracing::{info, instrument, Level};
+
+use crate::{
+    state::{AssetState, ItemState, State},
+    Id,
+};
+
+/// Name of the JSON change report, stored in the course directory.
+pub(crate) const CHANGES_FILE_NAME: &str = ".elopage-dl-changes.json";
+
+/// Changes to a course's lessons, categories and assets since the previous run.
+#[derive(Debug, Default, Serialize)]
+pub(crate) struct Changes {
+    pub new_items: Vec<ItemChange>,
+    pub removed_items: Vec<ItemChange>,
+    pub renamed_items: Vec<ItemRename>,
+    pub new_assets: Vec<AssetChange>,
+    pub changed_assets: Vec<AssetChange>,
+    pub removed_assets: Vec<AssetChange>,
+}
+
+/// A lesson or category which was added or removed.
+#[derive(Debug, Serialize)]
+pub(crate) struct ItemChange {
+    pub id: Id,
+    pub name: String,
+    pub is_category: bool,
+}
+
+/// A lesson or category which was renamed.
+#[derive(Debug, Serialize)]
+pub(crate) struct ItemRename {
+    pub id: Id,
+    pub is_category: bool,
+    pub previous_name: String,
+    pub name: String,
+}
+
+/// An asset which was added, changed or removed.
+#[derive(Debug, Serialize)]
+pub(crate) struct AssetChange {
+    pub lesson_id: Id,
+    pub lesson_name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub previous: Option<AssetState>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub current: Option<AssetState>,
+}
+
+impl Changes {
+    /// Compare the state recorded by the previous run with the state recorded by this run.
+    #[instrument(level = Level::DEBUG, skip_all)]
+    pub(crate) fn between(previous: &State, current: &State) -> Self {
+        let mut changes = Self::default();
+
+        for (id, item) in &current.items {
+            let Some(previous_item) = previous.items.get(id) else {
+                changes.new_items.push(ItemChange::new(*id, item));
+                continue;
+            };
+
+            if previous_item.name != item.name {
+                changes.renamed_items.push(ItemRename {
+                    id: *id,
+                    is_category: item.is_category,
+                    previous_name: previous_item.name.clone(),
+                    name: item.name.clone(),
+                });
+            }
+
+            if !item.is_category {
+                changes.diff_assets(*id, previous_item, item);
+            }
+        }
+
+        for (id, previous_item) in &previous.items {
+            if !current.items.contains_key(id) {
+                changes
+                    .removed_items
+                    .push(ItemChange::new(*id, previous_item));
+            }
+        }
+
+        changes
+    }
+
+    fn diff_assets(&mut self, lesson_id: Id, previous: &ItemState, current: &ItemState) {
+        let previous_assets: BTreeMap<_, _> = previous
+            .assets
+            .iter()
+            .map(|asset| (asset.key(), asset))
+            .collect();
+        let current_assets: BTreeMap<_, _> = current
+            .assets
+            .iter()
+            .map(|asset| (asset.key(), asset))
+            .collect();
+
+        let change =
+            |previous: Option<&AssetState>, current_asset: Option<&AssetState>| AssetChange {
+                lesson_id,
+                lesson_name: current.name.clone(),
+                previous: previous.cloned(),
+                current: current_asset.cloned(),
+            };
+
+        for (key, asset) in &current_assets {
+            match previous_assets.get(key) {
+                None => self.new_assets.push(change(None, Some(asset))),
+                Some(previous_asset) if !asset.is_unchanged(previous_asset) => self
+                    .changed_assets
+                    .push(change(Some(previous_asset), Some(asset))),
+                Some(_) => {}
+            }
+        }
+
+        for (key, previous_asset) in &previous_assets {
+            if !current_assets.contains_key(key) {
+                self.removed_assets.push(change(Some(previous_asset), None));
+            }
+        }
+    }
+
+    /// Add stored assets which were updated in place on the server, as detected while downloading,
+    /// by their lesson IDs and indices in the current state.
+    pub(crate) fn add_updated(
+        &mut self,
+        updated: &[(Id, usize)],
+        previous: &State,
+        current: &State,
+    ) {
+        for &(lesson_id, index) in updated {
+            let Some(item) = current.items.get(&lesson_id) else {
+                continue;
+            };
+            let Some(asset) = item.assets.get(index) else {
+                continue;
+            };
+            let previous_asset = previous.items.get(&lesson_id).and_then(|previous_item| {
+                previous_item
+                    .assets
+                    .iter()
+                    .find(|previous_asset| previous_asset.key() == asset.key())
+            });
+
+            self.changed_assets.push(AssetChange {
+                lesson_id,
+                lesson_name: item.name.clone(),
+                previous: previous_asset.cloned(),
+                current: Some(asset.clone()),
+            });
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.new_items.is_empty()
+            && self.removed_items.is_empty()
+            && self.renamed_items.is_empty()
+            && self.new_assets.is_empty()
+            && self.changed_assets.is_empty()
+            && self.removed_assets.is_empty()
+    }
+
+    /// Write the changes as JSON to the course directory, for automation.
+    #[instrument(level = Level::DEBUG, skip(self))]
+    pub(crate) async fn save(&self, course_path: &Path) -> Result<()> {
+        fs::write(
+            course_path.join(CHANGES_FILE_NAME),
+            serde_json::to_vec_pretty(self)?,
+        )
+        .await
+        .wrap_err("Failed to write change report")
+    }
+
+    /// Log a human-readable changelog.
+    pub(crate) fn log(&self) {
+        if self.is_empty() {
+            info!("No changes to the course since the previous run.");
+            return;
+        }
+
+        info!("Changes to the course since the previous run:");
+        for item in &self.new_items {
+            info!("  + New {}: {}", item.kind(), item.name);
+        }
+        for item in &self.removed_items {
+            info!("  - Removed {}: {}", item.kind(), item.name);
+        }
+        for rename in &self.renamed_items {
+            info!(
+                "  ~ Renamed {}: {} -> {}",
+                kind(rename.is_category),
+                rename.previous_name,
+                rename.name
+            );
+        }
+        for change in &self.new_assets {
+            info!("  + New asset in {}: {}", change.lesson_name, change.name());
+        }
+        for change in &self.changed_assets {
+            info!(
+                "  ~ Changed asset in {}: {}",
+                change.lesson_name,
+                change.name()
+            );
+        }
+        for change in &self.removed_assets {
+            info!(
+                "  - Removed asset from {}: {}",
+                change.lesson_name,
+                change.name()
+            );
+        }
+    }
+}
+
+impl ItemChange {
+    fn new(id: Id, item: &ItemState) -> Self {
+        Self {
+            id,
+            name: item.name.clone(),
+            is_category: item.is_category,
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        kind(self.is_category)
+    }
+}
+
+impl AssetChange {
+    fn name(&self) -> &str {
+        self.current
+            .as_ref()
+            .or(self.previous.as_ref())
+            .map(AssetState::key)
+            .unwrap_or_default()
+    }
+}
+
+fn kind(is_category: bool) -> &'static str {
+    if is_category {
+        "category"
+    } else {
+        "lesson"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn state(items: serde_json::Value) -> State {
+        serde_json::from_value(json!({ "items": items })).unwrap()
+    }
+
+    fn lesson(name: &str, assets: serde_json::Value) -> serde_json::Value {
+        json!({ "name": name, "is_category": false, "path": name, "assets": assets })
+    }
+
+    #[test]
+    fn reports_new_removed_and_renamed_items() {
+        let previous = state(json!({
+            "1": { "name": "Basics", "is_category": true, "path": "Basics" },
+            "2": lesson("Intro", json!([])),
+            "3": lesson("Outro", json!([])),
+        }));
+        let current = state(json!({
+            "1": { "name": "Fundamentals", "is_category": true, "path": "Fundamentals" },
+            "2": lesson("Intro", json!([])),
+            "4": lesson("Bonus", json!([])),
+        }));
+
+        let changes = Changes::between(&previous, &current);
+
+        assert_eq!(
+            changes
+                .new_items
+                .iter()
+                .map(|item| item.id)
+                .collect::<Vec<_>>(),
+            [4]
+        );
+        assert_eq!(
+            changes
+                .removed_items
+                .iter()
+                .map(|item| item.id)
+                .collect::<Vec<_>>(),
+            [3]
+        );
+        assert_eq!(changes.renamed_items.len(), 1);
+        assert_eq!(changes.renamed_items[0].id, 1);
+        assert!(changes.renamed_items[0].is_category);
+        assert_eq!(changes.renamed_items[0].previous_name, "Basics");
+        assert_eq!(changes.renamed_items[0].name, "Fundamentals");
+        assert!(changes.new_assets.is_empty() && changes.removed_assets.is_empty());
+    }
+
+    #[test]
+    fn reports_new_changed_and_removed_assets() {
+        let previous = state(json!({
+            "2": lesson("Intro", json!([
+                { "source": "https://cdn.example.com/a.pdf?signature=1", "file_name": "a.pdf", "size": 10 },
+                { "source": "https://cdn.example.com/b.pdf", "file_name": "b.pdf", "size": 20 },
+                { "source": "https://cdn.example.com/c.pdf", "file_name": "c.pdf", "size": 30 },
+            ])),
+        }));
+        let current = state(json!({
+            "2": lesson("Intro", json!([
+                // Only the expiring signature changed.
+                { "source": "https://cdn.example.com/a.pdf?signature=2", "file_name": "a.pdf", "size": 10 },
+                { "source": "https://cdn.example.com/b.pdf", "file_name": "b.pdf", "size": 25 },
+                { "source": "https://cdn.example.com/d.pdf", "file_name": "d.pdf", "size": 40 },
+            ])),
+        }));
+
+        let changes = Changes::between(&previous, &current);
+        let names = |changes: &[AssetChange]| {
+            changes
+                .iter()
+                .map(|change| change.name().to_owned())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(names(&changes.new_assets), ["d.pdf"]);
+        assert_eq!(names(&changes.changed_assets), ["b.pdf"]);
+        assert_eq!(names(&changes.removed_assets), ["c.pdf"]);
+        assert!(changes.new_items.is_empty() && changes.renamed_items.is_empty());
+        assert!(!changes.is_empty());
+        assert!(Changes::between(&current, &current).is_empty());
+    }
+}
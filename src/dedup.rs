@@ -0,0 +1,351 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use clap::ValueEnum;
+use color_eyre::Result;
+use tracing::{debug, info, instrument, warn, Level};
+
+use crate::{
+    integrity::{self, FileStatus},
+    license::find_courses,
+    lock,
+    state::{without_query, Checksum, State, Validators},
+    Downloaded,
+};
+
+/// How to store a file which is stored in the output directory already.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub(crate) enum Dedup {
+    /// Hard link to the stored file. All names share one file, so modifying it modifies all of them.
+    Hardlink,
+    /// Copy-on-write clone of the stored file, on file systems which support it, such as Btrfs, XFS and APFS.
+    Reflink,
+}
+
+/// Files stored in the output directory, to link instead of downloading them again.
+#[derive(Debug)]
+pub(crate) struct Deduplicator {
+    method: Dedup,
+    inner: Mutex<StoredFiles>,
+}
+
+#[derive(Debug, Default)]
+struct StoredFiles {
+    /// Stored files by download URL, without the query string, which might carry an expiring signature.
+    by_source: HashMap<String, Vec<StoredFile>>,
+    /// Stored files by SHA-256 hash.
+    by_content: HashMap<String, Vec<StoredFile>>,
+    /// Files which were verified against their checksums, or downloaded by this run.
+    verified: HashSet<PathBuf>,
+}
+
+#[derive(Clone, Debug)]
+struct StoredFile {
+    path: PathBuf,
+    checksum: Checksum,
+    validators: Option<Validators>,
+}
+
+impl Deduplicator {
+    /// Index the completely downloaded files of all offline-cached courses in `output_dir`.
+    #[instrument(level = Level::DEBUG)]
+    pub(crate) async fn load(method: Dedup, output_dir: &Path) -> Result<Self> {
+        let mut stored_files = StoredFiles::default();
+
+        if tokio::fs::try_exists(output_dir).await? {
+            for course in find_courses(output_dir).await? {
+                let state = State::load(&course.path).await?;
+                for item in state.items.values() {
+                    for asset in item.assets.iter().filter(|asset| !asset.pending) {
+                        let (Some(file_name), Some(checksum)) = (&asset.file_name, &asset.checksum)
+                        else {
+                            continue;
+                        };
+                        stored_files.insert(
+                            &asset.source,
+                            StoredFile {
+                                path: course.path.join(&item.path).join(file_name),
+                                checksum: checksum.clone(),
+                                validators: asset.validators.clone(),
+                            },
+                        );
+                    }
+                }
+            }
+        }
+        debug!(
+            "Indexed {} stored file(s) for deduplication.",
+            stored_files.by_source.values().map(Vec::len).sum::<usize>()
+        );
+
+        Ok(Self {
+            method,
+            inner: Mutex::new(stored_files),
+        })
+    }
+
+    /// Record a file downloaded by this run.
+    pub(crate) fn insert(&self, source: &str, path: &Path, downloaded: &Downloaded) {
+        let mut stored_files = self.lock();
+        stored_files.insert(
+            source,
+            StoredFile {
+                path: path.to_owned(),
+                checksum: downloaded.checksum.clone(),
+                validators: downloaded.validators.clone(),
+            },
+        );
+        stored_files.verified.insert(path.to_owned());
+    }
+
+    /// Store the file downloaded from `source` at `path` by linking an intact copy stored before, if there is one.
+    ///
+    /// Falls back to copying the stored file, if it cannot be linked.
+    pub(crate) async fn link_by_source(&self, source: &str, path: &Path) -> Option<Downloaded> {
+        let candidates = self
+            .lock()
+            .by_source
+            .get(without_query(source))
+            .cloned()
+            .unwrap_or_default();
+
+        for candidate in candidates
+            .into_iter()
+            .filter(|candidate| candidate.path != path)
+        {
+            if !self.verify(&candidate).await {
+                continue;
+            }
+
+            match link(&candidate.path, path, self.method, true).await {
+                Ok(()) => {
+                    info!(
+                        "Linked '{}' to '{}', which was downloaded before.",
+                        path.display(),
+                        candidate.path.display()
+                    );
+                    self.lock().verified.insert(path.to_owned());
+                    return Some(Downloaded {
+                        checksum: candidate.checksum,
+                        validators: candidate.validators,
+                    });
+                }
+                Err(error) => warn!(
+                    "Failed to copy '{}' to '{}': {error}",
+                    candidate.path.display(),
+                    path.display()
+                ),
+            }
+        }
+
+        None
+    }
+
+    /// Replace a freshly downloaded file by a link to an identical file stored before, to save disk space.
+    pub(crate) async fn link_by_content(&self, path: &Path, checksum: &Checksum) {
+        let candidates = self
+            .lock()
+            .by_content
+            .get(&checksum.sha256)
+            .cloned()
+            .unwrap_or_default();
+
+        for candidate in candidates.into_iter().filter(|candidate| {
+            candidate.path != path && candidate.checksum.length == checksum.length
+        }) {
+            if !self.verify(&candidate).await {
+                continue;
+            }
+
+            // Copying would not save any space.
+            match link(&candidate.path, path, self.method, false).await {
+                Ok(()) => {
+                    info!(
+                        "Linked '{}' to the identical '{}'.",
+                        path.display(),
+                        candidate.path.display()
+                    );
+                    return;
+                }
+                Err(error) => debug!(
+                    "Failed to link '{}' to '{}': {error}",
+                    path.display(),
+                    candidate.path.display()
+                ),
+            }
+        }
+    }
+
+    /// Ensure that a stored file is intact, re-hashing it once per run.
+    async fn verify(&self, stored_file: &StoredFile) -> bool {
+        if self.lock().verified.contains(&stored_file.path) {
+            return true;
+        }
+
+        match integrity::verify_file(&stored_file.path, &stored_file.checksum).await {
+            Ok(FileStatus::Intact) => {
+                self.lock().verified.insert(stored_file.path.clone());
+                true
+            }
+            Ok(status) => {
+                debug!("Not linking '{}': {status}", stored_file.path.display());
+                false
+            }
+            Err(report) => {
+                debug!("Not linking '{}': {report:#}", stored_file.path.display());
+                false
+            }
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, StoredFiles> {
+        lock(&self.inner)
+    }
+}
+
+impl StoredFiles {
+    fn insert(&mut self, source: &str, stored_file: StoredFile) {
+        self.by_content
+            .entry(stored_file.checksum.sha256.clone())
+            .or_default()
+            .push(stored_file.clone());
+        self.by_source
+            .entry(without_query(source).to_owned())
+            .or_default()
+            .push(stored_file);
+    }
+}
+
+/// Link `original` to `path`, replacing any file at `path`, optionally falling back to copying.
+///
+/// The link is created under a temporary name first, so that `path` is replaced atomically.
+async fn link(original: &Path, path: &Path, method: Dedup, copy_fallback: bool) -> io::Result<()> {
+    let original = original.to_owned();
+    let path = path.to_owned();
+
+    tokio::task::spawn_blocking(move || {
+        let mut temporary_name = path.file_name().unwrap_or_default().to_owned();
+        temporary_name.push(".link");
+        let temporary_path = path.with_file_name(temporary_name);
+        let _ = std::fs::remove_file(&temporary_path);
+
+        let linked = match method {
+            Dedup::Hardlink => std::fs::hard_link(&original, &temporary_path),
+            Dedup::Reflink => reflink_copy::reflink(&original, &temporary_path),
+        };
+        match linked {
+            Ok(()) if method == Dedup::Hardlink => {}
+            Ok(()) => copy_modified(&original, &temporary_path)?,
+            Err(error) if copy_fallback => {
+                debug!(
+                    "Failed to link '{}', copying it instead: {error}",
+                    original.display()
+                );
+                std::fs::copy(&original, &temporary_path)?;
+                copy_modified(&original, &temporary_path)?;
+            }
+            Err(error) => return Err(error),
+        }
+
+        std::fs::rename(&temporary_path, &path).inspect_err(|_| {
+            let _ = std::fs::remove_file(&temporary_path);
+        })
+    })
+    .await?
+}
+
+/// Give a copy the modification time of its original, as a hard link has.
+fn copy_modified(original: &Path, copy: &Path) -> io::Result<()> {
+    let modified = std::fs::metadata(original)?.modified()?;
+    std::fs::File::options()
+        .write(true)
+        .open(copy)?
+        .set_modified(modified)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = "https://cdn.example.com/workbook.pdf";
+
+    async fn deduplicator(method: Dedup, stored: &Path, contents: &[u8]) -> Deduplicator {
+        std::fs::write(stored, contents).unwrap();
+        let deduplicator = Deduplicator {
+            method,
+            inner: Mutex::new(StoredFiles::default()),
+        };
+        deduplicator.insert(
+            &format!("{SOURCE}?signature=1"),
+            stored,
+            &Downloaded {
+                checksum: integrity::hash_file(stored).await.unwrap(),
+                validators: None,
+            },
+        );
+        deduplicator
+    }
+
+    #[tokio::test]
+    async fn links_files_by_source() {
+        let dir = tempfile::tempdir().unwrap();
+        let stored = dir.path().join("stored.pdf");
+        let path = dir.path().join("linked.pdf");
+        let deduplicator = deduplicator(Dedup::Hardlink, &stored, b"%PDF").await;
+
+        let linked = deduplicator
+            .link_by_source(&format!("{SOURCE}?signature=2"), &path)
+            .await
+            .unwrap();
+
+        assert_eq!(linked.checksum.length, 4);
+        assert_eq!(std::fs::read(&path).unwrap(), b"%PDF");
+        assert!(deduplicator
+            .link_by_source("https://cdn.example.com/other.pdf", &path)
+            .await
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn replaces_identical_files_by_hard_links() {
+        let dir = tempfile::tempdir().unwrap();
+        let stored = dir.path().join("stored.pdf");
+        let path = dir.path().join("identical.pdf");
+        let deduplicator = deduplicator(Dedup::Hardlink, &stored, b"%PDF").await;
+        std::fs::write(&path, b"%PDF").unwrap();
+
+        deduplicator
+            .link_by_content(&path, &integrity::hash_file(&path).await.unwrap())
+            .await;
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+
+            assert_eq!(std::fs::metadata(&path).unwrap().nlink(), 2);
+            assert_eq!(
+                std::fs::metadata(&path).unwrap().ino(),
+                std::fs::metadata(&stored).unwrap().ino()
+            );
+        }
+        assert!(!dir.path().join("identical.pdf.link").exists());
+    }
+
+    #[tokio::test]
+    async fn does_not_link_modified_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let stored = dir.path().join("stored.pdf");
+        let path = dir.path().join("linked.pdf");
+        let deduplicator = deduplicator(Dedup::Hardlink, &stored, b"%PDF").await;
+        // Files recorded by this run are trusted, while files stored before are verified.
+        lock(&deduplicator.inner).verified.clear();
+        std::fs::write(&stored, b"%PDF, modified").unwrap();
+
+        assert!(deduplicator.link_by_source(SOURCE, &path).await.is_none());
+        assert!(!path.exists());
+    }
+}
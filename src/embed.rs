@@ -0,0 +1,579 @@
+use std::{collections::HashMap, fmt::Debug};
+
+use clap::ValueEnum;
+use regex::Regex;
+use reqwest::Url;
+use tracing::warn;
+
+use crate::args::DownloadArgs;
+
+/// The kind of HTML element an embed was found in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum EmbedElement {
+    /// `<iframe>` players.
+    Iframe,
+    /// `<video>`, `<audio>` and `<source>` elements.
+    Media,
+    /// `<a>` links.
+    Link,
+}
+
+/// How to download an embed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum EmbedDownload {
+    /// Download with `yt-dlp`, passing extra arguments.
+    YtDlp { url: String, args: Vec<String> },
+    /// Stream a media file to disk, like attached files.
+    File { url: String },
+}
+
+/// A video or audio host, whose players are embedded into or linked from lesson texts.
+pub(crate) trait EmbedProvider: Debug + Send + Sync {
+    /// Name of the provider, as used by `--embed-args`.
+    fn name(&self) -> &str;
+
+    /// The normalized URL of a player or link found in an element, if it belongs to this provider.
+    fn detect(&self, url: &Url, element: EmbedElement) -> Option<String>;
+
+    /// Plan the download of a detected embed, with the user's extra `yt-dlp` arguments for this provider.
+    fn plan(&self, url: String, args: &[String]) -> EmbedDownload {
+        EmbedDownload::YtDlp {
+            url,
+            args: args.to_vec(),
+        }
+    }
+}
+
+/// The built-in embed providers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub(crate) enum BuiltinEmbed {
+    /// Vimeo videos and events.
+    Vimeo,
+    /// YouTube videos, including `youtube-nocookie.com` and `youtu.be`.
+    Youtube,
+    /// Wistia players (`fast.wistia.net`), unlike videos attached to lessons.
+    Wistia,
+    /// Loom recordings.
+    Loom,
+    /// Vidyard videos.
+    Vidyard,
+    /// Bunny Stream videos (`iframe.mediadelivery.net`).
+    Bunny,
+    /// JW Player videos (`cdn.jwplayer.com`).
+    Jwplayer,
+    /// SoundCloud tracks.
+    Soundcloud,
+    /// Audio and video files played by `<video>` and `<audio>` elements, downloaded directly.
+    Video,
+}
+
+impl BuiltinEmbed {
+    fn provider(self) -> Box<dyn EmbedProvider> {
+        match self {
+            BuiltinEmbed::Vimeo => Box::new(Vimeo),
+            BuiltinEmbed::Youtube => Box::new(Youtube),
+            BuiltinEmbed::Wistia => Box::new(Wistia),
+            BuiltinEmbed::Loom => Box::new(Loom),
+            BuiltinEmbed::Vidyard => Box::new(Vidyard),
+            BuiltinEmbed::Bunny => Box::new(Bunny),
+            BuiltinEmbed::Jwplayer => Box::new(JwPlayer),
+            BuiltinEmbed::Soundcloud => Box::new(SoundCloud),
+            BuiltinEmbed::Video => Box::new(PlainMedia),
+        }
+    }
+}
+
+/// A user-defined provider, detecting embeds by a regular expression on their URL, to be downloaded by `yt-dlp`.
+#[derive(Clone, Debug)]
+pub(crate) struct CustomEmbed {
+    name: String,
+    pattern: Regex,
+}
+
+impl EmbedProvider for CustomEmbed {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn detect(&self, url: &Url, _element: EmbedElement) -> Option<String> {
+        self.pattern
+            .is_match(url.as_str())
+            .then(|| url.as_str().to_owned())
+    }
+}
+
+/// Parse a custom embed provider, such as `panopto=^https://[^/]+\.panopto\.eu/`.
+pub(crate) fn parse_custom_embed(s: &str) -> Result<CustomEmbed, String> {
+    let (name, pattern) = s.split_once('=').ok_or_else(|| {
+        format!("Invalid custom embed provider '{s}', expected e.g. 'panopto=^https://[^/]+\\.panopto\\.eu/'")
+    })?;
+    let pattern = Regex::new(pattern.trim()).map_err(|error| error.to_string())?;
+
+    Ok(CustomEmbed {
+        name: name.trim().to_owned(),
+        pattern,
+    })
+}
+
+/// Parse extra `yt-dlp` arguments for a provider, such as `vimeo=--format bestvideo*+bestaudio`.
+pub(crate) fn parse_provider_args(s: &str) -> Result<(String, Vec<String>), String> {
+    let (name, args) = s.split_once('=').ok_or_else(|| {
+        format!("Invalid provider arguments '{s}', expected e.g. 'youtube=--format 22'")
+    })?;
+
+    Ok((
+        name.trim().to_owned(),
+        args.split_whitespace().map(str::to_owned).collect(),
+    ))
+}
+
+/// The enabled embed providers, along with their options.
+#[derive(Debug)]
+pub(crate) struct EmbedProviders {
+    /// Asked in order of registration, so that custom providers take precedence over built-in ones.
+    providers: Vec<Box<dyn EmbedProvider>>,
+    /// Extra `yt-dlp` arguments by provider name.
+    args: HashMap<String, Vec<String>>,
+}
+
+impl EmbedProviders {
+    /// The custom and built-in providers configured by `--custom-embed`, `--embed-providers` and `--embed-args`.
+    pub(crate) fn from_args(args: &DownloadArgs) -> Self {
+        let mut providers = Self {
+            providers: Vec::new(),
+            args: HashMap::new(),
+        };
+        for custom in &args.custom_embeds {
+            providers.register(Box::new(custom.clone()));
+        }
+        for builtin in &args.embed_providers {
+            providers.register(builtin.provider());
+        }
+
+        for (name, provider_args) in &args.embed_args {
+            if !providers
+                .providers
+                .iter()
+                .any(|provider| provider.name() == name)
+            {
+                warn!("Ignoring `--embed-args` for '{name}', which is no enabled embed provider.");
+            }
+            providers
+                .args
+                .entry(name.clone())
+                .or_default()
+                .extend(provider_args.iter().cloned());
+        }
+
+        providers
+    }
+
+    /// All built-in providers, without extra arguments.
+    #[cfg(test)]
+    pub(crate) fn builtin() -> Self {
+        Self {
+            providers: BuiltinEmbed::value_variants()
+                .iter()
+                .map(|builtin| builtin.provider())
+                .collect(),
+            args: HashMap::new(),
+        }
+    }
+
+    /// Register a provider, which is asked after all providers registered before.
+    pub(crate) fn register(&mut self, provider: Box<dyn EmbedProvider>) {
+        self.providers.push(provider);
+    }
+
+    /// Plan the download of a URL found in an element, by the first provider which detects it.
+    pub(crate) fn detect(&self, url: &str, element: EmbedElement) -> Option<EmbedDownload> {
+        let url = Url::parse(url).ok()?;
+
+        self.providers.iter().find_map(|provider| {
+            let normalized = provider.detect(&url, element)?;
+            let args = self
+                .args
+                .get(provider.name())
+                .map(Vec::as_slice)
+                .unwrap_or_default();
+            Some(provider.plan(normalized, args))
+        })
+    }
+}
+
+/// The lowercase host of a URL, without `www.` and `m.` prefixes.
+fn host(url: &Url) -> Option<String> {
+    let host = url.host_str()?.to_ascii_lowercase();
+    let host = host.strip_prefix("www.").unwrap_or(&host);
+    Some(host.strip_prefix("m.").unwrap_or(host).to_owned())
+}
+
+/// The non-empty path segments of a URL.
+fn segments(url: &Url) -> Vec<&str> {
+    url.path_segments()
+        .map(|segments| segments.filter(|segment| !segment.is_empty()).collect())
+        .unwrap_or_default()
+}
+
+/// Whether a host is the domain, or one of its subdomains.
+fn is_domain(host: &str, domain: &str) -> bool {
+    host == domain || host.ends_with(&format!(".{domain}"))
+}
+
+fn query(url: &Url, name: &str) -> Option<String> {
+    url.query_pairs()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.into_owned())
+}
+
+fn is_numeric(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|byte| byte.is_ascii_digit())
+}
+
+#[derive(Debug)]
+struct Vimeo;
+
+impl EmbedProvider for Vimeo {
+    fn name(&self) -> &str {
+        "vimeo"
+    }
+
+    /// Normalized to `https://player.vimeo.com/video/<id>`, keeping the hash of unlisted videos,
+    /// or `https://vimeo.com/event/<id>` for events.
+    fn detect(&self, url: &Url, _element: EmbedElement) -> Option<String> {
+        let video_url = |id: &str, hash: Option<&str>| match hash {
+            Some(hash) => format!("https://player.vimeo.com/video/{id}?h={hash}"),
+            None => format!("https://player.vimeo.com/video/{id}"),
+        };
+
+        match (host(url)?.as_str(), segments(url).as_slice()) {
+            ("player.vimeo.com", ["video", id, ..]) => {
+                Some(video_url(id, query(url, "h").as_deref()))
+            }
+            ("vimeo.com", ["event", id, ..]) => Some(format!("https://vimeo.com/event/{id}")),
+            ("vimeo.com", [id, hash, ..]) if is_numeric(id) => Some(video_url(id, Some(hash))),
+            ("vimeo.com", [id]) if is_numeric(id) => {
+                Some(video_url(id, query(url, "h").as_deref()))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Youtube;
+
+impl EmbedProvider for Youtube {
+    fn name(&self) -> &str {
+        "youtube"
+    }
+
+    /// Normalized to `https://www.youtube.com/embed/<id>`.
+    fn detect(&self, url: &Url, _element: EmbedElement) -> Option<String> {
+        let id = match (host(url)?.as_str(), segments(url).as_slice()) {
+            (
+                "youtube.com" | "youtube-nocookie.com",
+                ["embed" | "shorts" | "live" | "v", id, ..],
+            ) => (*id).to_owned(),
+            ("youtube.com", ["watch"]) => query(url, "v")?,
+            ("youtu.be", [id, ..]) => (*id).to_owned(),
+            _ => return None,
+        };
+
+        Some(format!("https://www.youtube.com/embed/{id}"))
+    }
+}
+
+#[derive(Debug)]
+struct Wistia;
+
+impl EmbedProvider for Wistia {
+    fn name(&self) -> &str {
+        "wistia"
+    }
+
+    /// Normalized to `https://fast.wistia.net/embed/iframe/<id>`.
+    fn detect(&self, url: &Url, _element: EmbedElement) -> Option<String> {
+        let host = host(url)?;
+        if !(is_domain(&host, "wistia.net") || is_domain(&host, "wistia.com")) {
+            return None;
+        }
+
+        match segments(url).as_slice() {
+            ["embed", "iframe" | "medias", id, ..] | ["medias", id, ..] => Some(format!(
+                "https://fast.wistia.net/embed/iframe/{}",
+                id.trim_end_matches(".jsonp")
+            )),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Loom;
+
+impl EmbedProvider for Loom {
+    fn name(&self) -> &str {
+        "loom"
+    }
+
+    /// Normalized to `https://www.loom.com/share/<id>`.
+    fn detect(&self, url: &Url, _element: EmbedElement) -> Option<String> {
+        match (host(url)?.as_str(), segments(url).as_slice()) {
+            ("loom.com", ["share" | "embed", id, ..]) => {
+                Some(format!("https://www.loom.com/share/{id}"))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Vidyard;
+
+impl EmbedProvider for Vidyard {
+    fn name(&self) -> &str {
+        "vidyard"
+    }
+
+    /// Normalized to `https://play.vidyard.com/<uuid>`.
+    fn detect(&self, url: &Url, _element: EmbedElement) -> Option<String> {
+        let id = match (host(url)?.as_str(), segments(url).as_slice()) {
+            ("play.vidyard.com", [id]) => *id,
+            ("share.vidyard.com", ["watch", id]) | ("embed.vidyard.com", ["share", id]) => *id,
+            _ => return None,
+        };
+        let id = id.split('.').next()?;
+
+        Some(format!("https://play.vidyard.com/{id}"))
+    }
+}
+
+#[derive(Debug)]
+struct Bunny;
+
+impl EmbedProvider for Bunny {
+    fn name(&self) -> &str {
+        "bunny"
+    }
+
+    /// Normalized to `https://iframe.mediadelivery.net/embed/<library>/<id>`.
+    fn detect(&self, url: &Url, _element: EmbedElement) -> Option<String> {
+        match (host(url)?.as_str(), segments(url).as_slice()) {
+            (
+                "iframe.mediadelivery.net" | "player.mediadelivery.net",
+                ["embed" | "play", library, id, ..],
+            ) => Some(format!(
+                "https://iframe.mediadelivery.net/embed/{library}/{id}"
+            )),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct JwPlayer;
+
+impl EmbedProvider for JwPlayer {
+    fn name(&self) -> &str {
+        "jwplayer"
+    }
+
+    /// Normalized to `https://cdn.jwplayer.com/v2/media/<id>`.
+    fn detect(&self, url: &Url, _element: EmbedElement) -> Option<String> {
+        let host = host(url)?;
+        if host != "cdn.jwplayer.com" && host != "content.jwplatform.com" {
+            return None;
+        }
+
+        // Player URLs name the media and the player, such as `players/<media>-<player>.html`.
+        let id = match segments(url).as_slice() {
+            ["players" | "previews" | "videos" | "manifests", id, ..] | ["v2", "media", id, ..] => {
+                id.split(['-', '.']).next()?
+            }
+            _ => return None,
+        };
+
+        (id.len() == 8 && id.bytes().all(|byte| byte.is_ascii_alphanumeric()))
+            .then(|| format!("https://cdn.jwplayer.com/v2/media/{id}"))
+    }
+}
+
+#[derive(Debug)]
+struct SoundCloud;
+
+impl EmbedProvider for SoundCloud {
+    fn name(&self) -> &str {
+        "soundcloud"
+    }
+
+    /// Normalized to the track's URL, without a query string.
+    fn detect(&self, url: &Url, _element: EmbedElement) -> Option<String> {
+        match host(url)?.as_str() {
+            // The player carries the track's API URL.
+            "w.soundcloud.com" => {
+                let track_url = Url::parse(&query(url, "url")?).ok()?;
+                is_domain(&host(&track_url)?, "soundcloud.com")
+                    .then(|| format!("https://api.soundcloud.com{}", track_url.path()))
+            }
+            "soundcloud.com" => match segments(url).as_slice() {
+                [user, track, ..] => Some(format!("https://soundcloud.com/{user}/{track}")),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct PlainMedia;
+
+impl EmbedProvider for PlainMedia {
+    fn name(&self) -> &str {
+        "video"
+    }
+
+    fn detect(&self, url: &Url, element: EmbedElement) -> Option<String> {
+        (element == EmbedElement::Media).then(|| url.as_str().to_owned())
+    }
+
+    fn plan(&self, url: String, _args: &[String]) -> EmbedDownload {
+        EmbedDownload::File { url }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_builtin_embeds() {
+        let providers = EmbedProviders::builtin();
+
+        let cases = [
+            (
+                "https://player.vimeo.com/video/123456?h=abcdef&autoplay=1",
+                EmbedElement::Iframe,
+                Some("https://player.vimeo.com/video/123456?h=abcdef"),
+            ),
+            (
+                "https://vimeo.com/123456/abcdef",
+                EmbedElement::Link,
+                Some("https://player.vimeo.com/video/123456?h=abcdef"),
+            ),
+            (
+                "https://vimeo.com/123456",
+                EmbedElement::Link,
+                Some("https://player.vimeo.com/video/123456"),
+            ),
+            (
+                "https://vimeo.com/event/42/embed",
+                EmbedElement::Iframe,
+                Some("https://vimeo.com/event/42"),
+            ),
+            ("https://vimeo.com/channels", EmbedElement::Link, None),
+            (
+                "https://www.youtube-nocookie.com/embed/dQw4w9WgXcQ?rel=0",
+                EmbedElement::Iframe,
+                Some("https://www.youtube.com/embed/dQw4w9WgXcQ"),
+            ),
+            (
+                "https://m.youtube.com/watch?v=dQw4w9WgXcQ&t=42",
+                EmbedElement::Link,
+                Some("https://www.youtube.com/embed/dQw4w9WgXcQ"),
+            ),
+            (
+                "https://youtu.be/dQw4w9WgXcQ",
+                EmbedElement::Link,
+                Some("https://www.youtube.com/embed/dQw4w9WgXcQ"),
+            ),
+            (
+                "https://fast.wistia.net/embed/iframe/abc123xyz?videoFoam=true",
+                EmbedElement::Iframe,
+                Some("https://fast.wistia.net/embed/iframe/abc123xyz"),
+            ),
+            (
+                "https://fast.wistia.com/embed/medias/abc123xyz.jsonp",
+                EmbedElement::Iframe,
+                Some("https://fast.wistia.net/embed/iframe/abc123xyz"),
+            ),
+            (
+                "https://notwistia.net/embed/iframe/abc123xyz",
+                EmbedElement::Iframe,
+                None,
+            ),
+            (
+                "https://www.loom.com/embed/0123abcd",
+                EmbedElement::Iframe,
+                Some("https://www.loom.com/share/0123abcd"),
+            ),
+            (
+                "https://share.vidyard.com/watch/AbCdEf123",
+                EmbedElement::Link,
+                Some("https://play.vidyard.com/AbCdEf123"),
+            ),
+            (
+                "https://play.vidyard.com/AbCdEf123.html?v=4",
+                EmbedElement::Iframe,
+                Some("https://play.vidyard.com/AbCdEf123"),
+            ),
+            (
+                "https://player.mediadelivery.net/play/1234/5678-abcd",
+                EmbedElement::Iframe,
+                Some("https://iframe.mediadelivery.net/embed/1234/5678-abcd"),
+            ),
+            (
+                "https://cdn.jwplayer.com/players/AbCd1234-XyZ98765.html",
+                EmbedElement::Iframe,
+                Some("https://cdn.jwplayer.com/v2/media/AbCd1234"),
+            ),
+            (
+                "https://content.jwplatform.com/videos/AbCd1234.mp4",
+                EmbedElement::Link,
+                Some("https://cdn.jwplayer.com/v2/media/AbCd1234"),
+            ),
+            (
+                "https://w.soundcloud.com/player/?url=https%3A//api.soundcloud.com/tracks/42&color=ff5500",
+                EmbedElement::Iframe,
+                Some("https://api.soundcloud.com/tracks/42"),
+            ),
+            (
+                "https://w.soundcloud.com/player/?url=https%3A//notsoundcloud.com/tracks/42",
+                EmbedElement::Iframe,
+                None,
+            ),
+            (
+                "https://soundcloud.com/artist/track?in=artist/sets/album",
+                EmbedElement::Link,
+                Some("https://soundcloud.com/artist/track"),
+            ),
+            ("https://example.com/lesson.html", EmbedElement::Link, None),
+        ];
+
+        for (url, element, expected) in cases {
+            let normalized = providers
+                .detect(url, element)
+                .map(|download| match download {
+                    EmbedDownload::YtDlp { url, .. } | EmbedDownload::File { url } => url,
+                });
+            assert_eq!(normalized.as_deref(), expected, "{url}");
+        }
+    }
+
+    #[test]
+    fn plans_media_files() {
+        let providers = EmbedProviders {
+            providers: vec![BuiltinEmbed::Video.provider()],
+            args: HashMap::new(),
+        };
+        let url = "https://cdn.example.com/intro.mp4";
+
+        assert_eq!(
+            providers.detect(url, EmbedElement::Media),
+            Some(EmbedDownload::File {
+                url: url.to_owned()
+            })
+        );
+        assert_eq!(providers.detect(url, EmbedElement::Link), None);
+    }
+}
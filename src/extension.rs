@@ -0,0 +1,652 @@
+use color_eyre::Result;
+use futures::stream::{self, StreamExt};
+use reqwest::{
+    header::{ACCEPT_ENCODING, CONTENT_DISPOSITION, CONTENT_RANGE, CONTENT_TYPE, RANGE},
+    Client, Response, StatusCode,
+};
+use tracing::{debug, instrument, Level};
+
+use crate::{asset_name, state::without_query, url_file_name, Context, Id, LessonAsset};
+
+/// Number of bytes fetched from the start of a file to sniff its type.
+const SNIFF_LENGTH: usize = 4096;
+
+/// File extensions by MIME type.
+const CONTENT_TYPE_EXTENSIONS: &[(&str, &str)] = &[
+    ("application/pdf", "pdf"),
+    ("application/zip", "zip"),
+    ("application/x-zip-compressed", "zip"),
+    ("application/epub+zip", "epub"),
+    ("application/msword", "doc"),
+    ("application/vnd.ms-excel", "xls"),
+    ("application/vnd.ms-powerpoint", "ppt"),
+    (
+        "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+        "docx",
+    ),
+    (
+        "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+        "xlsx",
+    ),
+    (
+        "application/vnd.openxmlformats-officedocument.presentationml.presentation",
+        "pptx",
+    ),
+    ("application/vnd.oasis.opendocument.text", "odt"),
+    ("application/vnd.oasis.opendocument.spreadsheet", "ods"),
+    ("application/vnd.oasis.opendocument.presentation", "odp"),
+    ("application/rtf", "rtf"),
+    ("application/json", "json"),
+    ("text/rtf", "rtf"),
+    ("text/plain", "txt"),
+    ("text/csv", "csv"),
+    ("text/html", "html"),
+    ("text/markdown", "md"),
+    ("image/jpeg", "jpg"),
+    ("image/png", "png"),
+    ("image/gif", "gif"),
+    ("image/webp", "webp"),
+    ("image/svg+xml", "svg"),
+    ("image/heic", "heic"),
+    ("image/tiff", "tif"),
+    ("audio/mpeg", "mp3"),
+    ("audio/mp4", "m4a"),
+    ("audio/x-m4a", "m4a"),
+    ("audio/aac", "aac"),
+    ("audio/wav", "wav"),
+    ("audio/x-wav", "wav"),
+    ("audio/ogg", "ogg"),
+    ("video/mp4", "mp4"),
+    ("video/x-m4v", "m4v"),
+    ("video/quicktime", "mov"),
+    ("video/webm", "webm"),
+    ("video/x-matroska", "mkv"),
+    ("video/x-msvideo", "avi"),
+];
+
+/// Extensions which denote the same kind of file, so that a name's extension is kept if it is equivalent to the detected one.
+///
+/// Office documents and e-books are ZIP archives, and MP4, M4A and QuickTime files share their container format.
+const EQUIVALENT_EXTENSIONS: &[&[&str]] = &[
+    &["jpg", "jpeg", "jpe"],
+    &["tif", "tiff"],
+    &["htm", "html"],
+    &["txt", "text", "md", "csv", "tsv", "srt", "vtt", "log"],
+    &[
+        "zip", "docx", "xlsx", "pptx", "epub", "odt", "ods", "odp", "key", "pages", "numbers",
+    ],
+    &["mp4", "m4v", "m4a", "mov", "3gp"],
+    &["mkv", "webm"],
+    &["ogg", "oga", "ogv", "opus"],
+];
+
+/// Extensions which do not tell the type of a file, and are replaced by the detected one.
+const GENERIC_EXTENSIONS: &[&str] = &["bin", "dat", "tmp"];
+
+/// What a request for the start of a file revealed about it.
+#[derive(Debug, Default)]
+pub(crate) struct Probe {
+    /// File name suggested by the `Content-Disposition` header.
+    pub file_name: Option<String>,
+    /// Extension of the file type detected from its content, or declared by the server.
+    pub extension: Option<&'static str>,
+    /// Size of the whole file, if announced.
+    pub size: Option<u64>,
+}
+
+/// Request the start of a file, to detect its type from its first bytes, the `Content-Disposition` file name,
+/// or the `Content-Type`, in this order.
+#[instrument(level = Level::DEBUG, skip(client))]
+pub(crate) async fn probe(client: &Client, url: &str) -> Result<Probe> {
+    let mut response = client
+        .get(url)
+        .header(RANGE, format!("bytes=0-{}", SNIFF_LENGTH - 1))
+        // The magic bytes are those of the file as stored, not of a compressed transfer.
+        .header(ACCEPT_ENCODING, "identity")
+        .send()
+        .await?
+        .error_for_status()?;
+
+    let file_name = header(&response, CONTENT_DISPOSITION.as_str())
+        .and_then(|disposition| disposition_file_name(&disposition));
+    let content_type = header(&response, CONTENT_TYPE.as_str());
+    let size = match response.status() {
+        StatusCode::PARTIAL_CONTENT => header(&response, CONTENT_RANGE.as_str())
+            .and_then(|range| range.rsplit_once('/')?.1.parse().ok()),
+        _ => response.content_length(),
+    };
+
+    // Servers which ignore the range send the whole file, which is cut off after the first chunks.
+    let mut head = Vec::new();
+    while head.len() < SNIFF_LENGTH {
+        match response.chunk().await? {
+            Some(chunk) => head.extend_from_slice(&chunk),
+            None => break,
+        }
+    }
+
+    let extension = sniff(&head)
+        .or_else(|| {
+            file_name
+                .as_deref()
+                .and_then(known_extension)
+                .and_then(canonical_extension)
+        })
+        .or_else(|| content_type.as_deref().and_then(content_type_extension));
+    debug!("Probed '{url}': {file_name:?}, {content_type:?}, {extension:?}, {size:?}");
+
+    Ok(Probe {
+        file_name,
+        extension,
+        size,
+    })
+}
+
+/// Resolve the names of a lesson's files and videos, such that their extensions match their types.
+///
+/// Video extensions are determined from the rendition's content type. Files are probed, unless their URL has a known
+/// extension, or the previous run recorded the same file with one, and the name given by the server is used for files without a name.
+/// Returns the sizes of probed files, by asset index.
+#[instrument(level = Level::DEBUG, skip(assets, context))]
+pub(crate) async fn resolve_names(
+    assets: &mut [LessonAsset],
+    lesson_id: Id,
+    context: &Context,
+) -> Result<Vec<Option<u64>>> {
+    let client = Client::new();
+
+    let resolved: Vec<(Option<String>, Option<u64>)> = stream::iter(assets.iter())
+        .map(|asset| {
+            let client = &client;
+            async move {
+                match asset {
+                    LessonAsset::File { url, name, .. } => {
+                        if let Some(extension) = name.as_ref().and_then(|_| {
+                            recorded_extension(url, lesson_id, context)
+                                .or_else(|| url_extension(url))
+                        }) {
+                            let name = asset_name(url, name)?;
+                            return Ok((Some(with_extension(&name, &extension)), None));
+                        }
+
+                        let probe = {
+                            let _permit = context.throttle.acquire(url).await;
+                            probe(client, url)
+                                .await
+                                .inspect_err(|error| {
+                                    debug!("Could not determine the type of '{url}': {error}")
+                                })
+                                .unwrap_or_default()
+                        };
+
+                        let name = match (name, probe.file_name) {
+                            (Some(name), _) => name.clone(),
+                            (None, Some(file_name)) => file_name,
+                            (None, None) => asset_name(url, name)?,
+                        };
+                        Ok((
+                            Some(match probe.extension {
+                                Some(extension) => with_extension(&name, extension),
+                                None => name,
+                            }),
+                            probe.size,
+                        ))
+                    }
+                    LessonAsset::Video {
+                        url,
+                        name,
+                        renditions,
+                        ..
+                    } => {
+                        let name = asset_name(url, name)?;
+                        let extension = renditions
+                            .iter()
+                            .find(|rendition| rendition.url == *url)
+                            .and_then(|rendition| rendition.content_type.as_deref())
+                            .and_then(content_type_extension);
+                        Ok((
+                            Some(match extension {
+                                Some(extension) => with_extension(&name, extension),
+                                None => name,
+                            }),
+                            None,
+                        ))
+                    }
+                    LessonAsset::Embed { .. } => Ok((None, None)),
+                }
+            }
+        })
+        .buffered(context.parallel.max(1))
+        .collect::<Vec<Result<_>>>()
+        .await
+        .into_iter()
+        .collect::<Result<_>>()?;
+
+    let mut sizes = Vec::new();
+    for (asset, (resolved_name, size)) in assets.iter_mut().zip(resolved) {
+        if let LessonAsset::File { name, .. } | LessonAsset::Video { name, .. } = asset {
+            if resolved_name.is_some() {
+                *name = resolved_name;
+            }
+        }
+        sizes.push(size);
+    }
+
+    Ok(sizes)
+}
+
+/// The known extension of the file the previous run recorded for the same URL, if any.
+///
+/// Thus, files are not probed again on every run in mirror mode.
+fn recorded_extension(url: &str, lesson_id: Id, context: &Context) -> Option<String> {
+    context
+        .previous_state()
+        .items
+        .get(&lesson_id)?
+        .assets
+        .iter()
+        .filter(|asset| without_query(&asset.source) == without_query(url))
+        .find_map(|asset| known_extension(asset.file_name.as_deref()?))
+        .filter(|extension| !GENERIC_EXTENSIONS.contains(&extension.as_str()))
+}
+
+/// The known extension of the file name in a URL, such as `pdf` of `https://cdn.example.com/workbook.pdf?token=1`.
+///
+/// Thus, files whose URL tells their type are not probed.
+fn url_extension(url: &str) -> Option<String> {
+    known_extension(&url_file_name(url)?)
+        .filter(|extension| !GENERIC_EXTENSIONS.contains(&extension.as_str()))
+}
+
+/// Give a name the detected extension, unless it already has an equivalent one.
+///
+/// Unknown extensions, such as in `Lesson 1.2`, are kept and appended to. Generic or contradicting extensions are replaced.
+pub(crate) fn with_extension(name: &str, extension: &str) -> String {
+    match name.rsplit_once('.') {
+        Some((stem, current)) if !stem.is_empty() && is_known(current) => {
+            if is_equivalent(current, extension) {
+                name.to_owned()
+            } else {
+                format!("{stem}.{extension}")
+            }
+        }
+        _ => format!("{name}.{extension}"),
+    }
+}
+
+/// The lowercase extension of a name, if it is a known file extension.
+fn known_extension(name: &str) -> Option<String> {
+    let (stem, extension) = name.rsplit_once('.')?;
+    (!stem.is_empty() && is_known(extension)).then(|| extension.to_ascii_lowercase())
+}
+
+/// The extension as a `'static` string, if it is known.
+fn canonical_extension(extension: String) -> Option<&'static str> {
+    CONTENT_TYPE_EXTENSIONS
+        .iter()
+        .map(|(_, known)| *known)
+        .chain(
+            EQUIVALENT_EXTENSIONS
+                .iter()
+                .flat_map(|family| family.iter().copied()),
+        )
+        .find(|known| *known == extension)
+}
+
+fn is_known(extension: &str) -> bool {
+    let extension = extension.to_ascii_lowercase();
+    GENERIC_EXTENSIONS.contains(&extension.as_str()) || canonical_extension(extension).is_some()
+}
+
+fn is_equivalent(a: &str, b: &str) -> bool {
+    let (a, b) = (a.to_ascii_lowercase(), b.to_ascii_lowercase());
+    a == b
+        || EQUIVALENT_EXTENSIONS
+            .iter()
+            .any(|family| family.contains(&a.as_str()) && family.contains(&b.as_str()))
+}
+
+/// The extension of a MIME type, ignoring parameters such as `; charset=utf-8`.
+pub(crate) fn content_type_extension(content_type: &str) -> Option<&'static str> {
+    let mime = content_type.split(';').next()?.trim().to_ascii_lowercase();
+    CONTENT_TYPE_EXTENSIONS
+        .iter()
+        .find(|(known, _)| *known == mime)
+        .map(|(_, extension)| *extension)
+}
+
+/// Detect a file type from the magic bytes at the start of the file.
+fn sniff(head: &[u8]) -> Option<&'static str> {
+    let at = |offset: usize, magic: &[u8]| head.get(offset..offset + magic.len()) == Some(magic);
+
+    Some(if at(0, b"%PDF-") {
+        "pdf"
+    } else if at(0, b"PK\x03\x04") {
+        sniff_zip(head)
+    } else if at(0, b"\xFF\xD8\xFF") {
+        "jpg"
+    } else if at(0, b"\x89PNG\r\n\x1A\n") {
+        "png"
+    } else if at(0, b"GIF87a") || at(0, b"GIF89a") {
+        "gif"
+    } else if at(0, b"RIFF") && at(8, b"WEBP") {
+        "webp"
+    } else if at(0, b"RIFF") && at(8, b"WAVE") {
+        "wav"
+    } else if at(0, b"RIFF") && at(8, b"AVI ") {
+        "avi"
+    } else if at(4, b"ftyp") {
+        if at(8, b"M4A ") {
+            "m4a"
+        } else if at(8, b"qt  ") {
+            "mov"
+        } else {
+            "mp4"
+        }
+    } else if at(0, b"ID3") || at(0, b"\xFF\xFB") || at(0, b"\xFF\xF3") || at(0, b"\xFF\xF2") {
+        "mp3"
+    } else if at(0, b"OggS") {
+        "ogg"
+    } else if at(0, b"\x1A\x45\xDF\xA3") {
+        if contains(head, b"webm") {
+            "webm"
+        } else {
+            "mkv"
+        }
+    } else if at(0, b"{\\rtf") {
+        "rtf"
+    } else {
+        return None;
+    })
+}
+
+/// Tell office documents and e-books apart from plain ZIP archives, by their first entries.
+///
+/// OpenDocument files and e-books start with an uncompressed `mimetype` entry, while Office Open XML documents
+/// contain `[Content_Types].xml` and their parts, such as `word/document.xml`.
+fn sniff_zip(head: &[u8]) -> &'static str {
+    let entries = zip_entries(head);
+
+    if let Some((_, mimetype)) = entries.first().filter(|(name, _)| *name == b"mimetype") {
+        match *mimetype {
+            b"application/epub+zip" => return "epub",
+            b"application/vnd.oasis.opendocument.text" => return "odt",
+            b"application/vnd.oasis.opendocument.spreadsheet" => return "ods",
+            b"application/vnd.oasis.opendocument.presentation" => return "odp",
+            _ => {}
+        }
+    }
+
+    if entries
+        .iter()
+        .any(|(name, _)| *name == b"[Content_Types].xml")
+    {
+        for (prefix, extension) in [(&b"word/"[..], "docx"), (b"xl/", "xlsx"), (b"ppt/", "pptx")] {
+            if entries.iter().any(|(name, _)| name.starts_with(prefix)) {
+                return extension;
+            }
+        }
+    }
+
+    "zip"
+}
+
+/// The names and stored data of the ZIP entries whose local file headers are within `head`.
+///
+/// Listing stops at an entry whose size is only given after its data.
+fn zip_entries(head: &[u8]) -> Vec<(&[u8], &[u8])> {
+    const HEADER_LENGTH: usize = 30;
+
+    let u16_at = |offset: usize| {
+        head.get(offset..offset + 2)
+            .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]) as usize)
+    };
+    let u32_at = |offset: usize| {
+        head.get(offset..offset + 4)
+            .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+    };
+
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    while head.get(offset..offset + 4) == Some(b"PK\x03\x04") {
+        let (Some(flags), Some(compressed_size), Some(name_length), Some(extra_length)) = (
+            u16_at(offset + 6),
+            u32_at(offset + 18),
+            u16_at(offset + 26),
+            u16_at(offset + 28),
+        ) else {
+            break;
+        };
+        let name_start = offset + HEADER_LENGTH;
+        let Some(name) = head.get(name_start..name_start + name_length) else {
+            break;
+        };
+        let data_start = name_start + name_length + extra_length;
+        let data_end = (data_start + compressed_size).min(head.len());
+        entries.push((name, head.get(data_start..data_end).unwrap_or_default()));
+
+        // The sizes follow the data in a data descriptor.
+        if flags & 0x08 != 0 {
+            break;
+        }
+        offset = data_start + compressed_size;
+    }
+
+    entries
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack
+        .windows(needle.len())
+        .any(|window| window == needle)
+}
+
+fn header(response: &Response, name: &str) -> Option<String> {
+    response
+        .headers()
+        .get(name)?
+        .to_str()
+        .ok()
+        .map(str::to_owned)
+}
+
+/// The file name of a `Content-Disposition` header, preferring the UTF-8 `filename*` parameter.
+fn disposition_file_name(disposition: &str) -> Option<String> {
+    let mut file_name = None;
+
+    for parameter in disposition.split(';').skip(1) {
+        let Some((key, value)) = parameter.split_once('=') else {
+            continue;
+        };
+        match key.trim().to_ascii_lowercase().as_str() {
+            "filename*" => {
+                // Such as `UTF-8''Workbook%20Week%201.pdf`.
+                if let Some((_, encoded)) = value.trim().split_once("''") {
+                    file_name = Some(percent_decode(encoded));
+                    break;
+                }
+            }
+            "filename" => file_name = Some(value.trim().trim_matches('"').to_owned()),
+            _ => {}
+        }
+    }
+
+    // Only the last path component is a file name.
+    file_name
+        .as_deref()
+        .and_then(|file_name| file_name.rsplit(['/', '\\']).next())
+        .map(str::trim)
+        .filter(|file_name| !file_name.is_empty())
+        .map(str::to_owned)
+}
+
+pub(crate) fn percent_decode(encoded: &str) -> String {
+    let bytes = encoded.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+
+    let mut index = 0;
+    while index < bytes.len() {
+        let hex = bytes
+            .get(index + 1..index + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[index], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                index += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                index += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sniffs_magic_bytes() {
+        let cases: &[(&[u8], Option<&str>)] = &[
+            (b"%PDF-1.7\n%\xE2\xE3\xCF\xD3", Some("pdf")),
+            (b"\x00\x00\x00\x20ftypisom\x00\x00\x02\x00", Some("mp4")),
+            (b"\x00\x00\x00\x14ftypqt  \x00\x00\x00\x00", Some("mov")),
+            (
+                b"\x1A\x45\xDF\xA3\x9F\x42\x86\x81\x01\x42\x82\x84webm",
+                Some("webm"),
+            ),
+            (
+                b"\x1A\x45\xDF\xA3\x9F\x42\x86\x81\x01\x42\x82\x88matroska",
+                Some("mkv"),
+            ),
+            (b"<!DOCTYPE html>", None),
+            (b"", None),
+        ];
+
+        for (head, expected) in cases {
+            assert_eq!(sniff(head), *expected, "{head:?}");
+        }
+    }
+
+    /// A ZIP archive of uncompressed entries.
+    fn zip(entries: &[(&str, &str)]) -> Vec<u8> {
+        let mut zip = Vec::new();
+        for (name, data) in entries {
+            zip.extend_from_slice(b"PK\x03\x04\x14\x00\x00\x00\x00\x00");
+            zip.extend_from_slice(&[0; 8]);
+            zip.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            zip.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            zip.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            zip.extend_from_slice(&0u16.to_le_bytes());
+            zip.extend_from_slice(name.as_bytes());
+            zip.extend_from_slice(data.as_bytes());
+        }
+        zip
+    }
+
+    #[test]
+    fn sniffs_zip_based_formats() {
+        let cases = [
+            (zip(&[("readme.txt", "Hello")]), "zip"),
+            (
+                zip(&[
+                    ("[Content_Types].xml", "<Types/>"),
+                    ("word/document.xml", ""),
+                ]),
+                "docx",
+            ),
+            (
+                zip(&[
+                    ("[Content_Types].xml", "<Types/>"),
+                    ("_rels/.rels", ""),
+                    ("xl/workbook.xml", ""),
+                ]),
+                "xlsx",
+            ),
+            (
+                zip(&[
+                    ("[Content_Types].xml", "<Types/>"),
+                    ("ppt/presentation.xml", ""),
+                ]),
+                "pptx",
+            ),
+            (zip(&[("mimetype", "application/epub+zip")]), "epub"),
+            (
+                zip(&[("mimetype", "application/vnd.oasis.opendocument.spreadsheet")]),
+                "ods",
+            ),
+            // Names which merely contain a part prefix, or a part without content types, are plain archives.
+            (
+                zip(&[("backup/word/notes.txt", ""), ("excel/xl/", "")]),
+                "zip",
+            ),
+            (zip(&[("word/document.xml", "")]), "zip"),
+            (zip(&[("notes.txt", "mimetypeapplication/epub+zip")]), "zip"),
+        ];
+
+        for (head, expected) in cases {
+            assert_eq!(sniff(&head), Some(expected), "{head:?}");
+        }
+        // Entries cut off at the end of the sniffed bytes are ignored.
+        let head = zip(&[
+            ("[Content_Types].xml", "<Types/>"),
+            ("word/document.xml", ""),
+        ]);
+        assert_eq!(sniff(&head[..head.len() - 5]), Some("zip"));
+    }
+
+    #[test]
+    fn takes_known_extensions_from_urls() {
+        assert_eq!(
+            url_extension("https://cdn.example.com/Workbook%201.PDF?token=1").as_deref(),
+            Some("pdf")
+        );
+        assert_eq!(url_extension("https://cdn.example.com/download.bin"), None);
+        assert_eq!(url_extension("https://cdn.example.com/files/1234"), None);
+        assert_eq!(url_extension("https://cdn.example.com/"), None);
+    }
+
+    #[test]
+    fn appends_or_replaces_extensions() {
+        assert_eq!(with_extension("Lesson 1.2", "pdf"), "Lesson 1.2.pdf");
+        assert_eq!(with_extension("Workbook", "pdf"), "Workbook.pdf");
+        assert_eq!(with_extension("Workbook.PDF", "pdf"), "Workbook.PDF");
+        assert_eq!(with_extension("Photo.jpeg", "jpg"), "Photo.jpeg");
+        assert_eq!(with_extension("Download.bin", "zip"), "Download.zip");
+        assert_eq!(with_extension("Slides.pdf", "pptx"), "Slides.pptx");
+        assert_eq!(with_extension(".hidden", "txt"), ".hidden.txt");
+    }
+
+    #[test]
+    fn parses_disposition_file_names() {
+        assert_eq!(
+            disposition_file_name(
+                "attachment; filename=\"Workbook.pdf\"; filename*=UTF-8''Workbook%20Woche%201%20%E2%80%93%20%C3%9Cbungen.pdf"
+            )
+            .as_deref(),
+            Some("Workbook Woche 1 – Übungen.pdf")
+        );
+        assert_eq!(
+            disposition_file_name("attachment; filename=\"Workbook.pdf\"").as_deref(),
+            Some("Workbook.pdf")
+        );
+        assert_eq!(
+            disposition_file_name("attachment; filename=\"../../etc/passwd\"").as_deref(),
+            Some("passwd")
+        );
+        assert_eq!(disposition_file_name("inline"), None);
+        assert_eq!(disposition_file_name("attachment; filename=\"\""), None);
+    }
+
+    #[test]
+    fn decodes_percent_escapes() {
+        assert_eq!(percent_decode("Week%201%20%C3%9Cbung"), "Week 1 Übung");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz%2"), "%zz%2");
+        assert_eq!(percent_decode("plain"), "plain");
+    }
+}
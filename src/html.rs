@@ -0,0 +1,283 @@
+use once_cell::sync::Lazy;
+use regex::Regex;
+use scraper::{ElementRef, Html};
+
+use crate::embed::{EmbedDownload, EmbedElement, EmbedProviders};
+
+static REGEX_CSS_URL: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(
+        r#"(?i)url\(\s*(?:"(?P<double>[^"]*)"|'(?P<single>[^']*)'|(?P<bare>[^)'"\s]+))\s*\)"#,
+    )
+    .unwrap()
+});
+
+/// The assets referenced by a lesson's HTML content, each in order of appearance and without duplicates.
+///
+/// Relative URLs, as well as `data:`, `mailto:` and other non-HTTP URLs, are skipped.
+#[derive(Debug, Default)]
+pub(crate) struct HtmlAssets {
+    /// Players and media elements, as well as links to videos, detected by an embed provider.
+    pub embeds: Vec<EmbedDownload>,
+    /// Images of `<img>` elements, in the largest size offered by their `srcset`,
+    /// and CSS backgrounds of `style` attributes and `<style>` elements.
+    pub images: Vec<String>,
+    /// Targets of `<a>` elements, other than embeds.
+    pub links: Vec<String>,
+}
+
+impl HtmlAssets {
+    /// Parse a lesson's HTML content, and extract the assets it references.
+    pub(crate) fn extract(html: &str, providers: &EmbedProviders) -> Self {
+        let document = Html::parse_fragment(html);
+        let mut assets = Self::default();
+
+        for element in document
+            .root_element()
+            .descendants()
+            .filter_map(ElementRef::wrap)
+        {
+            let attribute = |name: &str| {
+                element
+                    .value()
+                    .attr(name)
+                    .and_then(|value| absolute_url(value.trim()))
+            };
+
+            match element.value().name() {
+                // Lazy-loaded iframes carry their URL in `data-src`.
+                "iframe" => {
+                    if let Some(embed) = [attribute("src"), attribute("data-src")]
+                        .into_iter()
+                        .flatten()
+                        .find_map(|url| providers.detect(&url, EmbedElement::Iframe))
+                    {
+                        push_unique(&mut assets.embeds, embed);
+                    }
+                }
+                "video" | "audio" | "source" => {
+                    // `<source>` elements of `<picture>` elements offer image sizes.
+                    let is_picture_source = element.value().name() == "source"
+                        && element
+                            .parent()
+                            .and_then(ElementRef::wrap)
+                            .is_some_and(|parent| parent.value().name() == "picture");
+
+                    if let Some(url) = attribute("src")
+                        .or_else(|| attribute("data-src"))
+                        .filter(|_| !is_picture_source)
+                    {
+                        if let Some(embed) = providers.detect(&url, EmbedElement::Media) {
+                            push_unique(&mut assets.embeds, embed);
+                        }
+                    }
+                }
+                "img" => {
+                    if let Some(url) = element
+                        .value()
+                        .attr("srcset")
+                        .and_then(largest_candidate)
+                        .or_else(|| attribute("src"))
+                        .or_else(|| attribute("data-src"))
+                    {
+                        push_unique(&mut assets.images, url);
+                    }
+                }
+                "a" => {
+                    if let Some(url) = attribute("href") {
+                        match providers.detect(&url, EmbedElement::Link) {
+                            Some(embed) => push_unique(&mut assets.embeds, embed),
+                            None => push_unique(&mut assets.links, url),
+                        }
+                    }
+                }
+                "style" => {
+                    for url in css_urls(&element.text().collect::<String>()) {
+                        push_unique(&mut assets.images, url);
+                    }
+                }
+                _ => {}
+            }
+
+            if let Some(style) = element.value().attr("style") {
+                for url in css_urls(style) {
+                    push_unique(&mut assets.images, url);
+                }
+            }
+        }
+
+        assets
+    }
+}
+
+fn push_unique<T: PartialEq>(items: &mut Vec<T>, item: T) {
+    if !items.contains(&item) {
+        items.push(item);
+    }
+}
+
+/// The absolute URLs of a style sheet.
+fn css_urls(css: &str) -> Vec<String> {
+    REGEX_CSS_URL
+        .captures_iter(css)
+        .filter_map(|captures| {
+            captures
+                .name("double")
+                .or_else(|| captures.name("single"))
+                .or_else(|| captures.name("bare"))
+        })
+        .filter_map(|url| absolute_url(url.as_str().trim()))
+        .collect()
+}
+
+/// The absolute URL of the largest image candidate of a `srcset`, by width or pixel density descriptor.
+fn largest_candidate(srcset: &str) -> Option<String> {
+    srcset
+        .split(',')
+        .filter_map(|candidate| {
+            let mut parts = candidate.split_whitespace();
+            let url = absolute_url(parts.next()?)?;
+            let descriptor = parts.next().unwrap_or("1x");
+            let size: f64 = descriptor
+                .get(..descriptor.len().saturating_sub(1))
+                .and_then(|size| size.parse().ok())
+                .unwrap_or(1.0);
+            Some((url, size))
+        })
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(url, _)| url)
+}
+
+/// An absolute `http(s)` URL, resolving protocol-relative URLs to `https`.
+fn absolute_url(url: &str) -> Option<String> {
+    let url = match url.strip_prefix("//") {
+        Some(url) => format!("https://{url}"),
+        None => url.to_owned(),
+    };
+
+    let lowercase = url.to_ascii_lowercase();
+    (lowercase.starts_with("https://") || lowercase.starts_with("http://")).then_some(url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn extract(html: &str) -> HtmlAssets {
+        HtmlAssets::extract(html, &EmbedProviders::builtin())
+    }
+
+    fn images(html: &str) -> Vec<String> {
+        extract(html).images
+    }
+
+    fn yt_dlp(url: &str) -> EmbedDownload {
+        EmbedDownload::YtDlp {
+            url: url.to_owned(),
+            args: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn extracts_embeds_regardless_of_markup() {
+        let assets = extract(
+            r#"<p>Welcome!</p>
+            <iframe src='https://player.vimeo.com/video/123456?h=abcdef&amp;autoplay=1' allowfullscreen></iframe>
+            <iframe class="lazyload" data-src="https://www.youtube-nocookie.com/embed/dQw4w9WgXcQ?rel=0"></iframe>
+            <IFRAME SRC = "https://player.vimeo.com/video/123456?h=abcdef"></IFRAME>
+            <video controls poster="https://cdn.example.com/poster.jpg">
+                <source src="https://cdn.example.com/intro.mp4?a=1&amp;b=2" type="video/mp4">
+            </video>
+            <audio data-src='//cdn.example.com/meditation.mp3'></audio>
+            <a href="https://youtu.be/dQw4w9WgXcQ">Watch on YouTube</a>
+            <a href='https://elopage.com/files/workbook.pdf?name=Work%20book&amp;v=2'>Workbook</a>
+            <a href="/relative.pdf">Relative</a>
+            <a href="mailto:support@example.com">Support</a>"#,
+        );
+
+        assert_eq!(
+            assets.embeds,
+            [
+                yt_dlp("https://player.vimeo.com/video/123456?h=abcdef"),
+                yt_dlp("https://www.youtube.com/embed/dQw4w9WgXcQ"),
+                EmbedDownload::File {
+                    url: "https://cdn.example.com/intro.mp4?a=1&b=2".to_owned()
+                },
+                EmbedDownload::File {
+                    url: "https://cdn.example.com/meditation.mp3".to_owned()
+                },
+            ]
+        );
+        assert_eq!(
+            assets.links,
+            ["https://elopage.com/files/workbook.pdf?name=Work%20book&v=2"]
+        );
+        assert!(assets.images.is_empty());
+    }
+
+    #[test]
+    fn skips_picture_sources() {
+        let assets = extract(
+            r#"<picture>
+                <source srcset="https://cdn.example.com/slide.webp" src="https://cdn.example.com/slide.webp">
+                <img src="https://cdn.example.com/slide.png">
+            </picture>"#,
+        );
+
+        assert!(assets.embeds.is_empty());
+        assert_eq!(assets.images, ["https://cdn.example.com/slide.png"]);
+    }
+
+    #[test]
+    fn picks_largest_srcset_candidates() {
+        assert_eq!(
+            largest_candidate("https://cdn.example.com/a-480.png 480w, https://cdn.example.com/a-1024.png 1024w, https://cdn.example.com/a-800.png 800w").as_deref(),
+            Some("https://cdn.example.com/a-1024.png")
+        );
+        assert_eq!(
+            largest_candidate("//cdn.example.com/a.png, //cdn.example.com/a@2x.png 2x").as_deref(),
+            Some("https://cdn.example.com/a@2x.png")
+        );
+        assert_eq!(
+            largest_candidate("/a.png 1x, data:image/png;base64,AAAA 2x"),
+            None
+        );
+
+        assert_eq!(
+            images(
+                r#"<img src="https://cdn.example.com/small.png" srcset="https://cdn.example.com/small.png 1x, https://cdn.example.com/large.png 2x">"#
+            ),
+            ["https://cdn.example.com/large.png"]
+        );
+        // Relative `srcset` candidates fall back to `src`.
+        assert_eq!(
+            images(r#"<img src="https://cdn.example.com/a.png" srcset="/a.png 2x">"#),
+            ["https://cdn.example.com/a.png"]
+        );
+    }
+
+    #[test]
+    fn extracts_css_background_images() {
+        assert_eq!(
+            css_urls(
+                r#"background: URL( "https://cdn.example.com/a.png" ), url('https://cdn.example.com/b.png') no-repeat; background-image: url(//cdn.example.com/c.png), url(data:image/png;base64,AAAA), url(/d.png)"#
+            ),
+            [
+                "https://cdn.example.com/a.png",
+                "https://cdn.example.com/b.png",
+                "https://cdn.example.com/c.png",
+            ]
+        );
+
+        assert_eq!(
+            images(
+                r#"<style>.slide { background: url("https://cdn.example.com/slide.jpg") }</style>
+                <div style="background-image: url('https://cdn.example.com/diagram.svg')"></div>
+                <img src="https://cdn.example.com/diagram.svg">"#
+            ),
+            [
+                "https://cdn.example.com/slide.jpg",
+                "https://cdn.example.com/diagram.svg",
+            ]
+        );
+    }
+}
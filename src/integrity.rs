@@ -0,0 +1,317 @@
+use std::{
+    fmt::{self, Display, Formatter},
+    io::{ErrorKind, Read},
+    path::{Component, Path, PathBuf},
+};
+
+use color_eyre::{
+    eyre::{bail, WrapErr},
+    Result,
+};
+use sha2::{Digest, Sha256};
+use tokio::fs;
+use tracing::{info, instrument, Level};
+
+use crate::{
+    args::CacheArgs,
+    license::find_courses,
+    space::format_size,
+    state::{Checksum, State},
+};
+
+/// Name of the checksum file, stored in the course directory in the format of `sha256sum`.
+pub(crate) const SUMS_FILE_NAME: &str = "SHA256SUMS";
+
+/// Computes the length and SHA-256 hash of a file while it is streamed to disk.
+#[derive(Debug, Default)]
+pub(crate) struct StreamHasher {
+    hasher: Sha256,
+    length: u64,
+}
+
+impl StreamHasher {
+    pub(crate) fn update(&mut self, bytes: &[u8]) {
+        self.hasher.update(bytes);
+        self.length += bytes.len() as u64;
+    }
+
+    pub(crate) fn finish(self) -> Checksum {
+        Checksum {
+            length: self.length,
+            sha256: format!("{:x}", self.hasher.finalize()),
+        }
+    }
+}
+
+/// Compute the length and SHA-256 hash of a file on disk.
+pub(crate) async fn hash_file(path: &Path) -> std::io::Result<Checksum> {
+    let path = path.to_owned();
+
+    tokio::task::spawn_blocking(move || {
+        let mut file = std::fs::File::open(path)?;
+        let mut hasher = StreamHasher::default();
+        let mut buffer = vec![0; 256 * 1024];
+        loop {
+            match file.read(&mut buffer)? {
+                0 => return Ok(hasher.finish()),
+                read => hasher.update(&buffer[..read]),
+            }
+        }
+    })
+    .await?
+}
+
+/// A download which is not as long as expected, such as after the connection was cut, or if elopage recorded a stale size.
+#[derive(Debug)]
+pub(crate) struct LengthMismatch {
+    url: String,
+    length: u64,
+    source: &'static str,
+    expected: u64,
+}
+
+impl Display for LengthMismatch {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Downloaded {} bytes of '{}', but {} is {} bytes",
+            self.length, self.url, self.source, self.expected
+        )
+    }
+}
+
+impl std::error::Error for LengthMismatch {}
+
+/// Ensure that a download is as long as announced by the server, and as the asset's size recorded by elopage.
+pub(crate) fn verify_length(
+    url: &str,
+    checksum: &Checksum,
+    content_length: Option<u64>,
+    expected_size: Option<u64>,
+) -> Result<(), LengthMismatch> {
+    for (expected, source) in [
+        (content_length, "the Content-Length"),
+        (expected_size, "the size recorded by elopage"),
+    ] {
+        if let Some(expected) = expected.filter(|&expected| expected != checksum.length) {
+            return Err(LengthMismatch {
+                url: url.to_owned(),
+                length: checksum.length,
+                source,
+                expected,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Write the checksums of all completely downloaded files of a course into its `SHA256SUMS` file,
+/// which can be checked with `sha256sum -c` from within the course directory.
+#[instrument(level = Level::DEBUG, skip(state))]
+pub(crate) async fn write_sums(state: &State, course_path: &Path) -> Result<()> {
+    let mut sums = String::new();
+    for (path, checksum) in checksums(state) {
+        sums.push_str(&format!("{}  {}\n", checksum.sha256, portable_path(&path)));
+    }
+
+    let sums_path = course_path.join(SUMS_FILE_NAME);
+    let temporary_path = course_path.join(format!("{SUMS_FILE_NAME}.tmp"));
+    fs::write(&temporary_path, sums)
+        .await
+        .wrap_err("Failed to write checksum file")?;
+    fs::rename(&temporary_path, &sums_path)
+        .await
+        .wrap_err("Failed to move checksum file into place")?;
+
+    Ok(())
+}
+
+/// Re-hash all offline-cached courses, reporting missing, truncated or modified files.
+#[instrument(level = Level::DEBUG, skip(args))]
+pub(crate) async fn verify(args: CacheArgs) -> Result<()> {
+    let courses = find_courses(&args.output_dir).await?;
+    if courses.is_empty() {
+        println!(
+            "No offline-cached courses found in '{}'.",
+            args.output_dir.display()
+        );
+        return Ok(());
+    }
+
+    let mut failed = 0;
+    for course in courses {
+        println!("{}\n    {}", course.name(), course.path.display());
+
+        let state = State::load(&course.path).await?;
+        let mut verified = 0;
+        let mut verified_size = 0;
+        for (path, checksum) in checksums(&state) {
+            match verify_file(&course.path.join(&path), checksum).await? {
+                FileStatus::Intact => {
+                    verified += 1;
+                    verified_size += checksum.length;
+                }
+                status => {
+                    println!("    {status}: {}", path.display());
+                    failed += 1;
+                }
+            }
+        }
+
+        println!(
+            "    {verified} file(s) ({}) intact",
+            format_size(verified_size)
+        );
+    }
+
+    if failed > 0 {
+        bail!("{failed} file(s) failed verification.");
+    }
+    info!("All files are intact.");
+
+    Ok(())
+}
+
+/// The outcome of verifying a file against its checksum.
+#[derive(Debug)]
+pub(crate) enum FileStatus {
+    Intact,
+    Missing,
+    /// Shorter than downloaded.
+    Truncated {
+        length: u64,
+        expected: u64,
+    },
+    Modified,
+}
+
+impl Display for FileStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            FileStatus::Intact => write!(f, "INTACT"),
+            FileStatus::Missing => write!(f, "MISSING"),
+            FileStatus::Truncated { length, expected } => {
+                write!(f, "TRUNCATED to {length} of {expected} bytes")
+            }
+            FileStatus::Modified => write!(f, "MODIFIED"),
+        }
+    }
+}
+
+/// Re-hash a file, and compare it with its recorded checksum.
+pub(crate) async fn verify_file(path: &Path, checksum: &Checksum) -> Result<FileStatus> {
+    let actual = match hash_file(path).await {
+        Ok(actual) => actual,
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(FileStatus::Missing),
+        Err(error) => {
+            return Err(error).wrap_err_with(|| format!("Failed to read '{}'", path.display()))
+        }
+    };
+
+    Ok(if actual.length < checksum.length {
+        FileStatus::Truncated {
+            length: actual.length,
+            expected: checksum.length,
+        }
+    } else if actual != *checksum {
+        FileStatus::Modified
+    } else {
+        FileStatus::Intact
+    })
+}
+
+/// The recorded checksums of completely downloaded files, by path relative to the course directory.
+fn checksums(state: &State) -> Vec<(PathBuf, &Checksum)> {
+    let mut checksums: Vec<_> = state
+        .items
+        .values()
+        .flat_map(|item| {
+            item.assets
+                .iter()
+                .filter(|asset| !asset.pending)
+                .filter_map(move |asset| {
+                    Some((
+                        item.path.join(
+                            asset
+                                .file_name
+                                .as_ref()
+                                .or(asset.embed_file_name.as_ref())?,
+                        ),
+                        asset.checksum.as_ref()?,
+                    ))
+                })
+        })
+        .collect();
+    checksums.sort_by(|a, b| a.0.cmp(&b.0));
+
+    checksums
+}
+
+/// A relative path with `/` separators, as expected by `sha256sum` on any platform.
+fn portable_path(path: &Path) -> String {
+    path.components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name.to_string_lossy()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn checksum(length: u64) -> Checksum {
+        Checksum {
+            length,
+            sha256: "0".repeat(64),
+        }
+    }
+
+    #[test]
+    fn verifies_lengths() {
+        let url = "https://cdn.example.com/video.mp4";
+
+        assert!(verify_length(url, &checksum(10), Some(10), Some(10)).is_ok());
+        assert!(verify_length(url, &checksum(10), None, None).is_ok());
+        assert_eq!(
+            verify_length(url, &checksum(8), Some(10), None)
+                .unwrap_err()
+                .to_string(),
+            "Downloaded 8 bytes of 'https://cdn.example.com/video.mp4', but the Content-Length is 10 bytes"
+        );
+        assert!(verify_length(url, &checksum(10), Some(10), Some(12)).is_err());
+    }
+
+    #[test]
+    fn lists_checksums_of_files_and_embedded_videos() {
+        let sum = json!({ "length": 1, "sha256": "0".repeat(64) });
+        let state: State = serde_json::from_value(json!({
+            "items": {
+                "1": {
+                    "name": "Intro",
+                    "is_category": false,
+                    "path": "Intro",
+                    "assets": [
+                        { "source": "https://cdn.example.com/b.pdf", "file_name": "b.pdf", "checksum": sum },
+                        { "source": "https://vimeo.com/1", "embed_file_name": "Welcome [1].mp4", "checksum": sum },
+                        // Downloads which did not complete are not listed.
+                        { "source": "https://cdn.example.com/c.pdf", "file_name": "c.pdf", "checksum": sum, "pending": true },
+                        { "source": "https://youtu.be/2" },
+                    ],
+                },
+            },
+        }))
+        .unwrap();
+
+        let paths: Vec<_> = checksums(&state)
+            .into_iter()
+            .map(|(path, _)| portable_path(&path))
+            .collect();
+        assert_eq!(paths, ["Intro/Welcome [1].mp4", "Intro/b.pdf"]);
+    }
+}
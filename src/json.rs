@@ -13,6 +13,9 @@ pub(crate) struct CourseResponse {
 pub(crate) struct Course {
     pub seller: Seller,
     pub product: Product,
+    /// End of course access, if the course session is time-limited.
+    #[serde(default, alias = "access_until")]
+    pub expires_at: Option<String>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -47,6 +50,9 @@ pub(crate) struct LessonsListItem {
     pub is_category: bool,
     pub parent_id: Option<Id>,
     pub position: Position,
+    /// Whether this is the fallback category of orphaned items, which is not part of the course.
+    #[serde(skip)]
+    pub is_unsorted: bool,
 }
 
 #[derive(Clone, Debug)]
@@ -95,6 +101,8 @@ pub(crate) struct DigitalGood {
     // id: Id,
     pub wistia_data: Option<WistiaData>,
     pub file: Option<FileAsset>,
+    #[serde(default)]
+    pub updated_at: Option<String>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -110,6 +118,24 @@ pub(crate) struct Asset {
     pub url: String,
     #[serde(rename = "fileSize")]
     pub file_size: usize,
+    /// Such as `OriginalFile`, `HdMp4VideoFile` or `StillImageFile`.
+    #[serde(default)]
+    pub r#type: Option<String>,
+    #[serde(default, rename = "contentType")]
+    pub content_type: Option<String>,
+}
+
+impl Asset {
+    /// Whether the asset is a rendition of the video, rather than e.g. a thumbnail.
+    pub(crate) fn is_video(&self) -> bool {
+        self.content_type
+            .as_deref()
+            .is_none_or(|content_type| content_type.starts_with("video/"))
+            && self
+                .r#type
+                .as_deref()
+                .is_none_or(|r#type| !r#type.contains("Image"))
+    }
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -117,3 +143,31 @@ pub(crate) struct FileAsset {
     pub name: Option<String>,
     pub original: Option<String>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_course_access_expiry() {
+        let course = |expiry: &str| {
+            serde_json::from_str::<CourseResponse>(&format!(
+                r#"{{"data": {{"seller": {{"username": "chef", "full_name": "Chef"}}, "product": {{"name": "Cooking"}}{expiry}}}}}"#
+            ))
+            .unwrap()
+            .data
+            .expires_at
+        };
+
+        assert_eq!(
+            course(r#", "expires_at": "2024-12-31T23:59:59Z""#).as_deref(),
+            Some("2024-12-31T23:59:59Z")
+        );
+        assert_eq!(
+            course(r#", "access_until": "2024-12-31""#).as_deref(),
+            Some("2024-12-31")
+        );
+        assert_eq!(course(r#", "expires_at": null"#), None);
+        assert_eq!(course(""), None);
+    }
+}
@@ -0,0 +1,285 @@
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use async_recursion::async_recursion;
+use color_eyre::{eyre::WrapErr, Result};
+use tokio::{
+    fs::{self, OpenOptions},
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+};
+use tracing::{debug, info, instrument, warn, Level};
+
+use crate::{
+    args::{CacheArgs, PurgeArgs},
+    state::{CourseState, State, STATE_FILE_NAME},
+};
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Parse a date such as `2024-12-31`, or an RFC 3339 timestamp such as `2024-12-31T23:59:59Z`.
+pub(crate) fn parse_date(s: &str) -> Result<SystemTime, humantime::TimestampError> {
+    humantime::parse_rfc3339_weak(s).or_else(|error| {
+        // A date without time: the license is valid until the end of the day.
+        if s.len() == 10 {
+            humantime::parse_rfc3339_weak(&format!("{s} 23:59:59"))
+        } else {
+            Err(error)
+        }
+    })
+}
+
+/// Format a timestamp for the course state.
+pub(crate) fn format_date(time: SystemTime) -> String {
+    humantime::format_rfc3339_seconds(time).to_string()
+}
+
+/// Whole days until the given time, negative if it has passed.
+pub(crate) fn days_remaining(expires: SystemTime) -> i64 {
+    match expires.duration_since(SystemTime::now()) {
+        Ok(remaining) => (remaining.as_secs() / SECONDS_PER_DAY) as i64,
+        Err(error) => -(error.duration().as_secs().div_ceil(SECONDS_PER_DAY) as i64),
+    }
+}
+
+/// A course directory found in the target-dir.
+#[derive(Debug)]
+pub(crate) struct CachedCourse {
+    pub path: PathBuf,
+    course: Option<CourseState>,
+    /// `None` if no expiry was recorded, or if it could not be parsed.
+    expires: Option<SystemTime>,
+}
+
+impl CachedCourse {
+    pub(crate) fn name(&self) -> String {
+        match &self.course {
+            Some(course) => format!("{} (ID {}) by {}", course.name, course.id, course.seller),
+            None => "Unknown course".into(),
+        }
+    }
+
+    fn is_expired(&self) -> bool {
+        self.expires
+            .is_some_and(|expires| expires <= SystemTime::now())
+    }
+}
+
+/// List all offline-cached courses, with the days remaining until their license expires.
+#[instrument(level = Level::DEBUG, skip(args))]
+pub(crate) async fn status(args: CacheArgs) -> Result<()> {
+    let courses = find_courses(&args.output_dir).await?;
+
+    if courses.is_empty() {
+        println!(
+            "No offline-cached courses found in '{}'.",
+            args.output_dir.display()
+        );
+    }
+
+    for course in courses {
+        let expiry = match course.expires {
+            Some(expires) => match days_remaining(expires) {
+                days if days < 0 => format!("license EXPIRED {} day(s) ago", -days),
+                days => format!("{days} day(s) remaining"),
+            },
+            None => "no license expiry recorded".into(),
+        };
+        println!(
+            "{}\n    {}\n    {expiry}",
+            course.name(),
+            course.path.display()
+        );
+    }
+
+    Ok(())
+}
+
+/// Delete all offline-cached courses whose license has expired.
+#[instrument(level = Level::DEBUG, skip(args))]
+pub(crate) async fn purge(args: PurgeArgs) -> Result<()> {
+    let expired: Vec<_> = find_courses(&args.cache.output_dir)
+        .await?
+        .into_iter()
+        .filter(CachedCourse::is_expired)
+        .collect();
+
+    if expired.is_empty() {
+        println!("No expired courses found.");
+        return Ok(());
+    }
+
+    let mut stdin = BufReader::new(tokio::io::stdin()).lines();
+
+    for course in expired {
+        if args.dry_run {
+            println!(
+                "Would delete {}: '{}'",
+                course.name(),
+                course.path.display()
+            );
+            continue;
+        }
+
+        if !args.yes {
+            eprint!(
+                "Delete {}: '{}'? [y/N] ",
+                course.name(),
+                course.path.display()
+            );
+            let answer = stdin.next_line().await?.unwrap_or_default();
+            if !matches!(answer.trim(), "y" | "Y" | "yes") {
+                println!("Skipped '{}'.", course.path.display());
+                continue;
+            }
+        }
+
+        info!("Deleting '{}'...", course.path.display());
+        overwrite_files_recursive(&course.path).await?;
+        fs::remove_dir_all(&course.path)
+            .await
+            .wrap_err("Failed to delete course directory")?;
+        println!("Deleted '{}'.", course.path.display());
+    }
+
+    Ok(())
+}
+
+/// Find all course directories, identified by their state file, below the given directory.
+#[async_recursion]
+pub(crate) async fn find_courses(dir: &Path) -> Result<Vec<CachedCourse>> {
+    if fs::try_exists(dir.join(STATE_FILE_NAME)).await? {
+        let course = State::load(dir).await?.course;
+        let expires = course
+            .as_ref()
+            .and_then(|course| course.license_expires.as_deref())
+            .and_then(|expires| {
+                parse_date(expires)
+                    .inspect_err(|error| warn!("Invalid license expiry '{expires}': {error}"))
+                    .ok()
+            });
+
+        return Ok(vec![CachedCourse {
+            path: dir.to_owned(),
+            course,
+            expires,
+        }]);
+    }
+
+    let mut courses = Vec::new();
+    let mut entries = fs::read_dir(dir)
+        .await
+        .wrap_err_with(|| format!("Failed to read directory '{}'", dir.display()))?;
+    while let Some(entry) = entries.next_entry().await? {
+        if entry.file_type().await?.is_dir() {
+            courses.extend(find_courses(&entry.path()).await?);
+        }
+    }
+
+    courses.sort_by(|a, b| a.path.cmp(&b.path));
+
+    Ok(courses)
+}
+
+/// Overwrite the contents of all files below the given directory with zeros, before they are deleted.
+///
+/// This prevents the course contents from being trivially recovered after deletion.
+/// Note that on flash storage and copy-on-write file systems, overwritten blocks might survive regardless.
+///
+/// Files with other hard links, such as those stored by `--dedup hardlink`, are only unlinked,
+/// since overwriting them would wipe the same file in other courses.
+/// Where hard links cannot be detected, files are never overwritten.
+#[async_recursion]
+async fn overwrite_files_recursive(dir: &Path) -> Result<()> {
+    static ZEROS: [u8; 64 * 1024] = [0; 64 * 1024];
+
+    let mut entries = fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let file_type = entry.file_type().await?;
+        if file_type.is_dir() {
+            overwrite_files_recursive(&entry.path()).await?;
+        } else if file_type.is_file() {
+            if may_be_hard_linked(&entry.metadata().await?) {
+                debug!(
+                    "Not overwriting '{}', which might have other hard links.",
+                    entry.path().display()
+                );
+                continue;
+            }
+
+            let mut file = OpenOptions::new().write(true).open(entry.path()).await?;
+            let mut remaining = file.metadata().await?.len();
+            while remaining > 0 {
+                let chunk = remaining.min(ZEROS.len() as u64) as usize;
+                file.write_all(&ZEROS[..chunk]).await?;
+                remaining -= chunk as u64;
+            }
+            file.sync_all().await?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn may_be_hard_linked(metadata: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    metadata.nlink() > 1
+}
+
+#[cfg(not(unix))]
+fn may_be_hard_linked(_metadata: &std::fs::Metadata) -> bool {
+    // The number of links is not available on this platform.
+    true
+}
+
+/// Warn if the license of a course expires soon, or has expired.
+pub(crate) fn warn_if_expiring(expires: SystemTime) {
+    const WARN_BEFORE: Duration = Duration::from_secs(14 * SECONDS_PER_DAY);
+
+    match days_remaining(expires) {
+        days if days < 0 => warn!(
+            "Your license for this course expired {} day(s) ago! You must delete the offline-cached course. See `elopage-dl purge`.",
+            -days
+        ),
+        days if expires
+            .duration_since(SystemTime::now())
+            .is_ok_and(|remaining| remaining < WARN_BEFORE) =>
+        {
+            warn!("Your license for this course expires in {days} day(s).")
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_dates_and_timestamps() {
+        let end_of_day = humantime::parse_rfc3339("2024-12-31T23:59:59Z").unwrap();
+
+        assert_eq!(parse_date("2024-12-31").unwrap(), end_of_day);
+        assert_eq!(parse_date("2024-12-31T23:59:59Z").unwrap(), end_of_day);
+        assert_eq!(parse_date("2024-12-31 23:59:59").unwrap(), end_of_day);
+        assert!(parse_date("31.12.2024").is_err());
+        assert!(parse_date("2024-13-01").is_err());
+        assert_eq!(format_date(end_of_day), "2024-12-31T23:59:59Z");
+    }
+
+    #[test]
+    fn counts_whole_days_remaining() {
+        const HOUR: Duration = Duration::from_secs(60 * 60);
+        const DAY: Duration = Duration::from_secs(SECONDS_PER_DAY);
+        let now = SystemTime::now();
+
+        assert_eq!(days_remaining(now + 3 * DAY + HOUR), 3);
+        assert_eq!(days_remaining(now + HOUR), 0);
+        // Expiry is counted from the first day after it passed.
+        assert_eq!(days_remaining(now - HOUR), -1);
+        assert_eq!(days_remaining(now - 2 * DAY - HOUR), -3);
+    }
+}
@@ -0,0 +1,194 @@
+use std::collections::HashSet;
+
+use futures::stream::{self, StreamExt};
+use reqwest::{header::CONTENT_TYPE, Client};
+use tracing::{debug, instrument, Level};
+
+use crate::{
+    args::DownloadArgs, extension::content_type_extension, state::without_query, url_file_name,
+    Context, Id, LessonAsset,
+};
+
+/// Which files linked from lesson texts are downloaded.
+#[derive(Debug)]
+pub(crate) struct LinkFilter {
+    /// Lowercase file extensions, without a leading dot.
+    extensions: Vec<String>,
+    /// Lowercase host names, which also match their subdomains. `*` matches any host.
+    hosts: Vec<String>,
+}
+
+impl LinkFilter {
+    /// The link filter configured by `--link-extensions` and `--link-hosts`, or `None` without `--links`.
+    pub(crate) fn from_args(args: &DownloadArgs) -> Option<Self> {
+        let normalize = |values: &[String]| {
+            values
+                .iter()
+                .map(|value| value.trim().trim_start_matches('.').to_ascii_lowercase())
+                .filter(|value| !value.is_empty())
+                .collect()
+        };
+
+        args.links.then(|| Self {
+            extensions: normalize(&args.link_extensions),
+            hosts: normalize(&args.link_hosts),
+        })
+    }
+
+    /// Whether a link points to an allowed host.
+    pub(crate) fn allows_host(&self, url: &str) -> bool {
+        let Some(host) = url
+            .parse::<reqwest::Url>()
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_ascii_lowercase))
+        else {
+            return false;
+        };
+
+        self.hosts.iter().any(|allowed| {
+            allowed == "*"
+                || host == *allowed
+                || host
+                    .strip_suffix(allowed.as_str())
+                    .is_some_and(|subdomain| subdomain.ends_with('.'))
+        })
+    }
+
+    fn allows_extension(&self, extension: &str) -> bool {
+        self.extensions
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(extension))
+    }
+}
+
+/// Keep only the linked files which are documents with an allowed extension, as indicated by their URL,
+/// or else by the `Content-Type` of a `HEAD` request.
+///
+/// Links downloaded by the previous run are kept without asking the server again.
+/// Links to attached files, and repeated links, are dropped.
+#[instrument(level = Level::DEBUG, skip(assets, context))]
+pub(crate) async fn retain_documents(
+    assets: Vec<LessonAsset>,
+    lesson_id: Id,
+    context: &Context,
+) -> Vec<LessonAsset> {
+    let Some(filter) = &context.links else {
+        return assets;
+    };
+    let client = Client::new();
+
+    // Links to files which are attached or shown anyway are not downloaded twice.
+    let other_urls: HashSet<String> = assets
+        .iter()
+        .filter_map(|asset| match asset {
+            LessonAsset::File {
+                url, linked: false, ..
+            }
+            | LessonAsset::Video { url, .. } => Some(without_query(url).to_owned()),
+            _ => None,
+        })
+        .collect();
+    let mut linked_urls = HashSet::new();
+    let assets: Vec<_> = assets
+        .into_iter()
+        .filter(|asset| match asset {
+            LessonAsset::File {
+                url, linked: true, ..
+            } => {
+                !other_urls.contains(without_query(url))
+                    && linked_urls.insert(without_query(url).to_owned())
+            }
+            _ => true,
+        })
+        .collect();
+
+    stream::iter(assets)
+        .map(|asset| {
+            let client = &client;
+            async move {
+                let LessonAsset::File {
+                    url, linked: true, ..
+                } = &asset
+                else {
+                    return Some(asset);
+                };
+
+                let url_extension =
+                    url_file_name(url).and_then(|name| Some(name.rsplit_once('.')?.1.to_owned()));
+                let is_document = if url_extension
+                    .as_deref()
+                    .is_some_and(|extension| filter.allows_extension(extension))
+                    || is_recorded(url, lesson_id, context)
+                {
+                    true
+                } else {
+                    let _permit = context.throttle.acquire(url).await;
+                    match client.head(url).send().await {
+                        Ok(response) => response
+                            .headers()
+                            .get(CONTENT_TYPE)
+                            .and_then(|value| value.to_str().ok())
+                            .and_then(content_type_extension)
+                            .is_some_and(|extension| filter.allows_extension(extension)),
+                        Err(error) => {
+                            debug!("Could not determine the type of '{url}': {error}");
+                            false
+                        }
+                    }
+                };
+
+                if !is_document {
+                    debug!("Not downloading '{url}', which is not a document.");
+                }
+                is_document.then_some(asset)
+            }
+        })
+        .buffered(context.parallel.max(1))
+        .filter_map(|asset| async move { asset })
+        .collect()
+        .await
+}
+
+/// Whether the previous run recorded a file downloaded from the URL in the lesson.
+fn is_recorded(url: &str, lesson_id: Id, context: &Context) -> bool {
+    context
+        .previous_state()
+        .items
+        .get(&lesson_id)
+        .is_some_and(|item| {
+            item.assets
+                .iter()
+                .any(|asset| without_query(&asset.source) == without_query(url))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(hosts: &[&str]) -> LinkFilter {
+        LinkFilter {
+            extensions: Vec::new(),
+            hosts: hosts.iter().map(|host| host.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn allows_hosts_and_their_subdomains() {
+        let filter = filter(&["elopage.com", "cloudfront.net"]);
+
+        assert!(filter.allows_host("https://elopage.com/files/a.pdf"));
+        assert!(filter.allows_host("https://files.ELOPAGE.com/a.pdf"));
+        assert!(filter.allows_host("http://d1234.cloudfront.net/a.pdf?token=1"));
+        assert!(!filter.allows_host("https://notelopage.com/a.pdf"));
+        assert!(!filter.allows_host("https://elopage.com.example.org/a.pdf"));
+        assert!(!filter.allows_host("https://example.org/elopage.com/a.pdf"));
+        assert!(!filter.allows_host("/files/a.pdf"));
+    }
+
+    #[test]
+    fn allows_any_host_with_a_wildcard() {
+        assert!(filter(&["*"]).allows_host("https://example.org/a.pdf"));
+        assert!(!filter(&[]).allows_host("https://elopage.com/a.pdf"));
+    }
+}
@@ -1,53 +1,96 @@
 use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
     ffi::OsStr,
     fmt::{Debug, Display},
+    future::Future,
     path::{Path, PathBuf},
     process::Stdio,
-    sync::Arc,
+    sync::{Arc, Mutex, MutexGuard},
+    time::SystemTime,
 };
 
 use async_recursion::async_recursion;
 use clap::Parser;
 use color_eyre::{
-    eyre::{eyre, Context},
+    eyre::{eyre, WrapErr},
     Report, Result,
 };
 use futures::{
     future::BoxFuture,
-    stream::{self, BoxStream, StreamExt, TryStreamExt},
+    stream::{self, StreamExt, TryStreamExt},
     FutureExt,
 };
-use once_cell::sync::Lazy;
-use regex::Regex;
 use reqwest::{
     header::{
-        HeaderMap, ACCEPT, ACCEPT_LANGUAGE, AUTHORIZATION, CONTENT_LANGUAGE, DNT, ORIGIN, REFERER,
-        USER_AGENT,
+        HeaderMap, ACCEPT, ACCEPT_LANGUAGE, AUTHORIZATION, CONTENT_LANGUAGE, DNT, ETAG,
+        IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, ORIGIN, REFERER, USER_AGENT,
     },
-    Client,
+    Client, Response, StatusCode,
 };
+use serde::de::DeserializeOwned;
 use tokio::{
-    fs::{create_dir_all, File},
+    fs::create_dir_all,
     io::{AsyncBufReadExt, AsyncRead, BufReader},
     process::{Child, Command},
     task::JoinHandle,
 };
 use tracing::{debug, error, info, instrument, warn, Level};
 
-use crate::args::Args;
+use crate::args::{Args, Commands, DownloadArgs};
+use crate::changes::Changes;
+use crate::dedup::Deduplicator;
+use crate::embed::{EmbedDownload, EmbedProviders};
+use crate::html::HtmlAssets;
+use crate::integrity::{LengthMismatch, StreamHasher};
 use crate::json::*;
+use crate::links::LinkFilter;
+use crate::metadata::{Source, SourceMetadata};
+use crate::partial::{PartialFile, YtDlpTempDir};
+use crate::rate::RateLimiter;
+use crate::sanitize::{SanitizeProfile, Sanitizer};
+use crate::shutdown::Shutdown;
+use crate::state::{
+    without_query, AssetState, Checksum, CourseState, ItemState, State, Validators,
+};
+use crate::summary::Summary;
+use crate::template::{index_width, TemplateVars, Templates, Variable};
+use crate::throttle::Throttle;
 
 mod args;
+mod changes;
+mod dedup;
+mod embed;
+mod extension;
+mod html;
+mod integrity;
 mod json;
+mod license;
+mod links;
+mod metadata;
+mod partial;
+mod queue;
+mod ranges;
+mod rate;
+mod repair;
+mod sanitize;
+mod shutdown;
+mod space;
+mod state;
+mod summary;
+mod template;
+mod throttle;
 mod trace;
+mod watch;
 
 type Id = usize;
 type Position = usize;
 
-static REGEX_VIDEO_IFRAME: Lazy<Regex> = Lazy::new(|| {
-    Regex::new(r#"<iframe[^>]* src="(?P<embed_url>https://(?:player\.vimeo\.com/video/|www.youtube.com/embed/)[^"]+)""#)
-        .unwrap()
-});
+/// Name of the fallback category into which orphaned lessons are placed.
+const UNSORTED_CATEGORY_NAME: &str = "Unsorted";
+
+/// Name of the folder into which `--mirror --archive` moves lessons and categories removed from the course.
+const ARCHIVE_DIR_NAME: &str = "Removed";
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -57,11 +100,56 @@ async fn main() -> Result<()> {
 
     trace::init(&args)?;
 
+    match args.command {
+        None => {
+            sync_course(&args.download, &Shutdown::listen()).await?;
+        }
+        Some(Commands::Watch(watch_args)) => watch::run(*watch_args, &Shutdown::listen()).await?,
+        Some(Commands::Status(cache_args)) => license::status(cache_args).await?,
+        Some(Commands::Purge(purge_args)) => license::purge(purge_args).await?,
+        Some(Commands::Verify(cache_args)) => integrity::verify(cache_args).await?,
+        Some(Commands::Repair(repair_args)) => {
+            repair::run(*repair_args, &Shutdown::listen()).await?
+        }
+    }
+
+    Ok(())
+}
+
+/// The result of downloading a course.
+#[derive(Debug)]
+struct SyncOutcome {
+    course_path: PathBuf,
+    /// Changes since the previous run, or `None` on the first run.
+    changes: Option<Changes>,
+}
+
+/// A course fetched from elopage, along with the directory it is stored in.
+#[derive(Debug)]
+struct OpenedCourse {
+    authenticated_client: Client,
+    course: Course,
+    /// Template variables of the course, shared by all of its items.
+    vars: TemplateVars,
+    path: PathBuf,
+    templates: Templates,
+    sanitizer: Sanitizer,
+    /// Files stored in the output directory, with `--dedup`.
+    deduplicator: Option<Deduplicator>,
+    throttle: Throttle,
+}
+
+/// Fetch the course, and resolve the directory it is stored in.
+#[instrument(level = Level::DEBUG, skip(args), fields(course_id = ?args.course_id))]
+async fn open_course(args: &DownloadArgs) -> Result<OpenedCourse> {
+    let templates = Templates::from_args(args)?;
+    let sanitizer = Sanitizer::new(args.sanitize, args.unicode_normalization);
+
     let mut default_headers = HeaderMap::new();
 
     default_headers.insert(ACCEPT, "application/json".parse()?);
     default_headers.insert(ACCEPT_LANGUAGE, args.language.parse()?);
-    default_headers.insert(AUTHORIZATION, args.token.parse()?);
+    default_headers.insert(AUTHORIZATION, args.token().parse()?);
     default_headers.insert(CONTENT_LANGUAGE, args.language.parse()?);
     default_headers.insert(ORIGIN, "https://elopage.com".parse()?);
     default_headers.insert(DNT, "1".parse()?);
@@ -72,64 +160,846 @@ async fn main() -> Result<()> {
         .default_headers(default_headers)
         .build()?;
 
-    let course = fetch_course(authenticated_client.clone(), args.course_id).await?;
+    let throttle = Throttle::new(
+        args.parallel_assets(),
+        &args.provider_limits,
+        args.adaptive_throttling,
+    );
+
+    let course = fetch_course(authenticated_client.clone(), &throttle, args.course_id()).await?;
+
+    let course_vars = TemplateVars::default()
+        .text(Variable::Seller, &course.seller.username)
+        .text(Variable::SellerName, &course.seller.full_name)
+        .text(Variable::Course, &course.product.name)
+        .number(Variable::CourseId, args.course_id(), 0);
+
+    let base_path =
+        PathBuf::from(args.output_dir()).join(templates.course.render(&course_vars, &sanitizer));
+
+    let deduplicator = match args.dedup {
+        Some(method) => Some(Deduplicator::load(method, Path::new(args.output_dir())).await?),
+        None => None,
+    };
+
+    Ok(OpenedCourse {
+        authenticated_client,
+        course,
+        vars: course_vars,
+        path: base_path,
+        templates,
+        sanitizer,
+        deduplicator,
+        throttle,
+    })
+}
 
-    let base_path = PathBuf::from(format!(
-        "{}/Elopage/{} ({})/{}",
-        args.output_dir,
-        safe_path(&course.seller.username),
-        safe_path(&course.seller.full_name),
-        safe_path(&course.product.name),
-    ));
+/// Download a course.
+///
+/// On shutdown, no new downloads are started, and the state is saved so that the next run can resume.
+#[instrument(level = Level::DEBUG, skip(args, shutdown), fields(course_id = ?args.course_id))]
+async fn sync_course(args: &DownloadArgs, shutdown: &Shutdown) -> Result<SyncOutcome> {
+    let opened = open_course(args).await?;
+    let course = &opened.course;
+    let course_vars = opened.vars.clone();
+    let base_path = opened.path.clone();
 
     // Fetch elopage's flat list of lessons and categories.
-    let lessons_list: Vec<LessonsListItem> =
-        fetch_lessons_list(authenticated_client.clone(), args.course_id)
-            .await?
-            .into_iter()
-            .filter(|item| item.active)
-            .collect();
+    let lessons_list: Vec<LessonsListItem> = fetch_lessons_list(
+        opened.authenticated_client.clone(),
+        &opened.throttle,
+        args.course_id(),
+    )
+    .await?
+    .into_iter()
+    .filter(|item| item.active)
+    .collect();
 
     // Transform the flat list of lessons and categories into a module tree,
     // where both categories and lessons can be either root items, or children of categories.
     let (module_tree, remaining_stack) = resolve_module_tree(None, lessons_list);
 
-    // We expect the entire stack to be part of the tree.
-    // `remaining_stack` would be non-empty if an item had a `parent_id` which was either not present in the stack,
-    // or it was present, but not a category (but a lesson).
-    if !remaining_stack.is_empty() {
-        warn!("Remaining stack left over after resolving module tree! Module tree: {module_tree:#?}, remaining stack: {remaining_stack:#?}");
-    }
-
     info!("Resolved module tree.");
     debug!("Module tree: {module_tree:#?}, remaining stack: {remaining_stack:#?}");
 
     // Some sellers use categories not as containers but merely as separators.
     // We normalize the tree structure by turning "separator categories" into "container categories".
     // To do this, we detect empty root categories and hoist root lessons into preceding empty root categories.
-    let module_tree = normalize_module_tree(module_tree);
+    let mut module_tree = normalize_module_tree(module_tree);
+
+    let summary = Summary::default();
+
+    // We expect the entire stack to be part of the tree.
+    // `remaining_stack` would be non-empty if an item had a `parent_id` which was either not present in the stack,
+    // or it was present, but not a category (but a lesson), or if items' `parent_id`s formed a cycle.
+    // Such orphaned items are still downloaded, but into a clearly named fallback category at the end of the course.
+    if !remaining_stack.is_empty() {
+        warn!(
+            "{} item(s) could not be placed into the module tree. Collecting them into the '{UNSORTED_CATEGORY_NAME}' category.",
+            remaining_stack.len()
+        );
+
+        let orphans = resolve_orphans(remaining_stack, &summary);
+        module_tree.push(ModuleTreeItem::Category {
+            item: LessonsListItem {
+                id: 0,
+                name: UNSORTED_CATEGORY_NAME.into(),
+                active: true,
+                content_page_id: None,
+                is_category: true,
+                parent_id: None,
+                position: Position::MAX,
+                is_unsorted: true,
+            },
+            children: orphans,
+        });
+    }
 
     // Recurse through the module tree, discovering linked and embedded assets,
-    // and create a stream of boxed download futures to process with a user-determined amount of parallelism.
+    // and plan the downloads, which the download queue runs with a user-determined amount of parallelism.
     // TODO: Lesson details are eagerly fetched while processing the tree. (`fetch_lesson_content_blocks`)
-    // TODO: It could be nice if they were lazily fetched whenever `StreamExt::buffered` (below) runs empty.
-    let downloads_stream = process_tree_recursive(
-        module_tree,
-        Arc::new(base_path),
-        args.course_id,
-        authenticated_client,
-        Arc::new(args.yt_dlp_bin),
-    )
-    .await?;
+    // TODO: It could be nice if they were lazily fetched whenever the download queue (`queue::run`) runs empty.
+    let previous_state = State::load(&base_path).await?;
+
+    // A license expiry passed by the user takes precedence over the course data, which takes precedence over the expiry recorded before.
+    let license_expires = args
+        .license_expires
+        .or_else(|| {
+            course.expires_at.as_deref().and_then(|expires_at| {
+                license::parse_date(expires_at)
+                    .inspect_err(|error| {
+                        warn!("Could not parse course access expiry '{expires_at}': {error}")
+                    })
+                    .ok()
+            })
+        })
+        .or_else(|| {
+            previous_state
+                .course
+                .as_ref()
+                .and_then(|course| course.license_expires.as_deref())
+                .and_then(|expires| license::parse_date(expires).ok())
+        });
+    if let Some(expires) = license_expires {
+        license::warn_if_expiring(expires);
+    }
 
-    // Download between 1 and `--parallel` assets in parallel.
-    downloads_stream
-        .buffered(args.parallel.clamp(1, usize::MAX))
-        // Shortcut on download errors.
-        .try_collect::<Vec<()>>()
+    let state = State {
+        course: Some(CourseState {
+            id: args.course_id(),
+            name: course.product.name.clone(),
+            seller: course.seller.full_name.clone(),
+            license_expires: license_expires.map(license::format_date),
+        }),
+        ..Default::default()
+    };
+    let context = Arc::new(Context::new(args, opened, previous_state, state, summary));
+
+    let planning = async {
+        let lessons = process_tree_recursive(
+            module_tree,
+            Arc::new(TreeLevel {
+                path: base_path,
+                depth: 1,
+                vars: course_vars,
+            }),
+            context.clone(),
+        )
         .await?;
 
-    Ok(())
+        let mut downloads = Vec::new();
+        for LessonAssets { lesson, mut assets } in lessons {
+            // Probing files reveals their sizes as well.
+            let sizes = extension::resolve_names(&mut assets, lesson.id, &context).await?;
+            let mut lesson_downloads = plan_lesson_downloads(assets, lesson, &context)?;
+            for (download, size) in lesson_downloads.iter_mut().zip(sizes) {
+                download.estimated_size = download.estimated_size.or(size);
+            }
+            downloads.extend(lesson_downloads);
+        }
+        for download in &mut downloads {
+            download.update_stored(&context);
+        }
+
+        // Estimate the course size, then fit it into the storage budget by selecting lower video renditions.
+        // Estimating asks `yt-dlp` about each embedded video, so it is skipped if the size is not checked.
+        if !args.ignore_free_space || args.max_total_size.is_some() {
+            space::estimate_sizes(&mut downloads, &context, args.parallel_assets()).await;
+        }
+        if let Some(max_total_size) = args.max_total_size {
+            space::fit_budget(&mut downloads, max_total_size)?;
+            // Videos stored in a different rendition are downloaded again.
+            for download in &mut downloads {
+                download.update_stored(&context);
+            }
+        }
+
+        Ok::<_, Report>(downloads)
+    };
+
+    let downloads = tokio::select! {
+        downloads = planning => match downloads {
+            Ok(downloads) => downloads,
+            Err(report) => {
+                // Lessons and categories might have been moved already.
+                context.save_merged_state().await?;
+                return Err(report);
+            }
+        },
+        _ = shutdown.aborting() => {
+            context.save_merged_state().await?;
+            warn!("Aborted before downloading. {}", resume_hint(args));
+            return Ok(SyncOutcome {
+                course_path: context.course_path.clone(),
+                changes: None,
+            });
+        }
+    };
+
+    // Lessons and categories have been placed - record their paths and assets before downloading.
+    record_assets(&downloads, &context);
+    context.save_state().await?;
+
+    // Compare the course with the previous run, unless this is the first run.
+    let mut changes = {
+        let previous_state = context.previous_state().clone();
+        (!previous_state.items.is_empty())
+            .then(|| Changes::between(&previous_state, &context.state()))
+    };
+    if let Some(changes) = &changes {
+        changes.save(&context.course_path).await?;
+    }
+
+    if args.mirror {
+        remove_missing_items(
+            &context,
+            if args.delete {
+                RemovedItems::Delete
+            } else if args.archive {
+                RemovedItems::Archive
+            } else {
+                RemovedItems::Keep
+            },
+        )
+        .await?;
+    }
+
+    if !args.ignore_free_space {
+        space::preflight(&downloads, &context.course_path)?;
+    }
+
+    run_downloads(downloads, &context, args, shutdown).await?;
+
+    // Files updated in place are only detected while downloading.
+    let updated_assets = context.take_updated_assets();
+    if let Some(changes) = changes.as_mut().filter(|_| !updated_assets.is_empty()) {
+        let previous_state = context.previous_state().clone();
+        let state = context.state().clone();
+        changes.add_updated(&updated_assets, &previous_state, &state);
+        changes.save(&context.course_path).await?;
+    }
+
+    if shutdown.is_stopping() {
+        warn!(
+            "Stopped before all files were downloaded. {}",
+            resume_hint(args)
+        );
+    }
+    context.summary.report();
+    if let Some(changes) = &changes {
+        changes.log();
+    }
+
+    Ok(SyncOutcome {
+        course_path: context.course_path.clone(),
+        changes,
+    })
+}
+
+/// How to continue an interrupted run. Only `--mirror` skips files which were already downloaded.
+fn resume_hint(args: &DownloadArgs) -> &'static str {
+    if args.mirror {
+        "Run again to resume."
+    } else {
+        "Run again with `--mirror` to resume, as otherwise all files are downloaded again."
+    }
+}
+
+/// Run the planned downloads, then record the completed downloads in the state and checksum files.
+///
+/// Files of up to `--parallel` lessons, and up to `--parallel-assets` files are downloaded in parallel, until shutdown is requested.
+async fn run_downloads(
+    downloads: Vec<PlannedDownload>,
+    context: &Arc<Context>,
+    args: &DownloadArgs,
+    shutdown: &Shutdown,
+) -> Result<()> {
+    let downloads = queue::run(
+        downloads,
+        |download| download.into_future(context.clone()),
+        queue::Limits {
+            lessons: args.parallel,
+            assets: args.parallel_assets(),
+        },
+        args.schedule,
+        shutdown,
+    );
+
+    // Cancelling the downloads removes their partial files.
+    let downloaded = tokio::select! {
+        downloaded = downloads => downloaded,
+        _ = shutdown.aborting() => Ok(()),
+    };
+
+    // Record completed downloads, even if another download failed.
+    context.save_state().await?;
+    let state = context.state().clone();
+    integrity::write_sums(&state, &context.course_path).await?;
+
+    downloaded
+}
+
+/// Run-wide state shared by module tree processing and downloads.
+#[derive(Debug)]
+struct Context {
+    authenticated_client: Client,
+    course_id: Id,
+    course_path: PathBuf,
+    yt_dlp_bin: PathBuf,
+    templates: Templates,
+    sanitizer: Sanitizer,
+    mirror: bool,
+    /// Maximum number of files downloaded at the same time.
+    parallel: usize,
+    /// Number of connections to download large files over.
+    connections: usize,
+    /// Minimum size in bytes of files downloaded over multiple connections.
+    chunked_min_size: u64,
+    /// Where to store the source of each downloaded file, if anywhere.
+    source_metadata: Option<SourceMetadata>,
+    /// Whether to download images shown in lesson texts.
+    images: bool,
+    /// Which files linked from lesson texts to download, if any.
+    links: Option<LinkFilter>,
+    /// Providers of videos embedded into lesson texts.
+    embed_providers: EmbedProviders,
+    /// Files to link instead of downloading them again, with `--dedup`.
+    deduplicator: Option<Deduplicator>,
+    /// Download rate limit shared by all native downloads.
+    rate_limiter: Arc<RateLimiter>,
+    /// Concurrency limits per provider.
+    throttle: Throttle,
+    /// State recorded by the previous run.
+    previous_state: Mutex<State>,
+    /// State recorded by this run.
+    state: Mutex<State>,
+    /// Lesson IDs and indices of stored assets which were updated in place on the server.
+    updated_assets: Mutex<Vec<(Id, usize)>>,
+    summary: Summary,
+}
+
+impl Context {
+    /// Set up the downloads of an opened course, starting this run's state from `state`.
+    fn new(
+        args: &DownloadArgs,
+        opened: OpenedCourse,
+        previous_state: State,
+        state: State,
+        summary: Summary,
+    ) -> Self {
+        let rate_limiter = Arc::new(RateLimiter::new(args.limit_rate, args.parallel_assets()));
+        if let Some(limit_rate_file) = &args.limit_rate_file {
+            rate_limiter.watch_control_file(limit_rate_file.clone());
+        }
+
+        Self {
+            authenticated_client: opened.authenticated_client,
+            course_id: args.course_id(),
+            course_path: opened.path,
+            yt_dlp_bin: args.yt_dlp_bin.clone(),
+            templates: opened.templates,
+            sanitizer: opened.sanitizer,
+            mirror: args.mirror,
+            parallel: args.parallel_assets(),
+            connections: args.connections.max(1),
+            chunked_min_size: args.chunked_min_size,
+            source_metadata: args.source_metadata,
+            images: args.images,
+            links: LinkFilter::from_args(args),
+            embed_providers: EmbedProviders::from_args(args),
+            deduplicator: opened.deduplicator,
+            rate_limiter,
+            throttle: opened.throttle,
+            previous_state: Mutex::new(previous_state),
+            state: Mutex::new(state),
+            updated_assets: Mutex::default(),
+            summary,
+        }
+    }
+
+    /// Persist the state recorded by this run.
+    async fn save_state(&self) -> Result<()> {
+        let state = self.state().clone();
+        state.save(&self.course_path).await
+    }
+
+    /// Persist the state of a run aborted while lessons and categories were placed.
+    ///
+    /// Items which were not placed yet keep their previous records, and placed items keep their previous assets.
+    async fn save_merged_state(&self) -> Result<()> {
+        let mut state = self.previous_state().clone();
+        let current = self.state().clone();
+        state.course = current.course;
+        state.unsorted = current.unsorted.or(state.unsorted);
+        for (id, mut item) in current.items {
+            if let Some(previous) = state.items.get(&id) {
+                if item.assets.is_empty() {
+                    item.assets = previous.assets.clone();
+                }
+            }
+            state.items.insert(id, item);
+        }
+        state.save(&self.course_path).await
+    }
+
+    /// Record an asset of a lesson as completely downloaded, along with the checksum and validators of its file.
+    fn asset_completed(
+        &self,
+        lesson_id: Id,
+        index: usize,
+        checksum: Option<Checksum>,
+        validators: Option<Validators>,
+    ) {
+        if let Some(asset) = self
+            .state()
+            .items
+            .get_mut(&lesson_id)
+            .and_then(|item| item.assets.get_mut(index))
+        {
+            asset.pending = false;
+            asset.checksum = checksum;
+            asset.validators = validators;
+        }
+    }
+
+    /// Record an embedded video as completely downloaded by `yt-dlp` to `file_name`.
+    fn embed_completed(
+        &self,
+        lesson_id: Id,
+        index: usize,
+        file_name: Option<String>,
+        checksum: Option<Checksum>,
+    ) {
+        if let Some(asset) = self
+            .state()
+            .items
+            .get_mut(&lesson_id)
+            .and_then(|item| item.assets.get_mut(index))
+        {
+            asset.embed_file_name = file_name;
+        }
+        self.asset_completed(lesson_id, index, checksum, None);
+    }
+
+    /// Record a stored asset of a lesson as updated in place on the server, and downloaded again.
+    fn asset_updated(&self, lesson_id: Id, index: usize) {
+        lock(&self.updated_assets).push((lesson_id, index));
+    }
+
+    /// Take the assets recorded as updated in place so far.
+    fn take_updated_assets(&self) -> Vec<(Id, usize)> {
+        std::mem::take(&mut lock(&self.updated_assets))
+    }
+
+    fn previous_state(&self) -> MutexGuard<'_, State> {
+        lock(&self.previous_state)
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        lock(&self.state)
+    }
+}
+
+/// Lock a mutex, recovering from poisoning.
+///
+/// The data guarded by this crate's mutexes stays consistent if a task panics while holding the lock.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// A level of the module tree: the directory its items are created in,
+/// and the template variables shared by its items, such as the parent category name.
+#[derive(Debug)]
+struct TreeLevel {
+    path: PathBuf,
+    depth: usize,
+    vars: TemplateVars,
+}
+
+/// An asset discovered in a lesson's content blocks.
+#[derive(Clone, Debug)]
+enum LessonAsset {
+    /// A file attached to a content block.
+    File {
+        url: String,
+        name: Option<String>,
+        /// Whether the file is linked from a content block's HTML content, rather than attached or embedded.
+        linked: bool,
+        /// Last modification, as recorded by elopage.
+        updated_at: Option<SystemTime>,
+    },
+    /// A Wistia video attached to a content block, in the selected rendition - by default, the largest.
+    Video {
+        url: String,
+        name: Option<String>,
+        size: usize,
+        /// All renditions of the video, largest first.
+        renditions: Vec<Asset>,
+        /// Last modification, as recorded by elopage.
+        updated_at: Option<SystemTime>,
+    },
+    /// A video embedded into or linked from a content block's HTML content, to be downloaded by `yt-dlp`.
+    Embed {
+        /// Normalized by the embed provider which detected it.
+        url: String,
+        /// Extra `yt-dlp` arguments for the embed provider.
+        args: Vec<String>,
+    },
+}
+
+impl LessonAsset {
+    /// Select the largest video rendition of at most `max_size` bytes, or the smallest rendition if none is small enough.
+    fn select_rendition(&mut self, max_size: u64) {
+        if let LessonAsset::Video {
+            url,
+            size,
+            renditions,
+            ..
+        } = self
+        {
+            if let Some(rendition) = space::rendition_within(renditions, max_size) {
+                *url = rendition.url.clone();
+                *size = rendition.file_size;
+            }
+        }
+    }
+}
+
+/// A lesson directory, along with the template variables shared by the lesson's assets.
+#[derive(Debug)]
+struct LessonDir {
+    id: Id,
+    path: PathBuf,
+    vars: TemplateVars,
+}
+
+/// A lesson, along with the assets discovered in its content blocks.
+#[derive(Debug)]
+struct LessonAssets {
+    lesson: Arc<LessonDir>,
+    assets: Vec<LessonAsset>,
+}
+
+/// A lesson asset with its resolved target, to be downloaded.
+#[derive(Debug)]
+struct PlannedDownload {
+    lesson: Arc<LessonDir>,
+    /// Index of the asset in order of appearance within its lesson.
+    index: usize,
+    asset: LessonAsset,
+    vars: TemplateVars,
+    /// Unique file name of natively downloaded assets. `None` for embedded videos, which are named by `yt-dlp`.
+    file_name: Option<String>,
+    /// Size in bytes, as served by Wistia, or as estimated before downloading. `None` if unknown.
+    estimated_size: Option<u64>,
+    /// Whether the asset was completely downloaded before and did not change since, so it is skipped in mirror mode.
+    ///
+    /// Embedded videos are passed to `yt-dlp` regardless, which skips videos it downloaded before.
+    is_stored: bool,
+    /// Checksum of the stored file, as recorded by the previous run.
+    checksum: Option<Checksum>,
+    /// HTTP validators of the stored file, as recorded by the previous run.
+    validators: Option<Validators>,
+}
+
+impl PlannedDownload {
+    fn path(&self) -> Option<PathBuf> {
+        self.file_name
+            .as_ref()
+            .map(|file_name| self.lesson.path.join(file_name))
+    }
+
+    /// The asset's record in the course state.
+    fn asset_state(&self) -> AssetState {
+        let (source, size) = match &self.asset {
+            LessonAsset::File { url, .. } => (url.clone(), None),
+            LessonAsset::Video { url, size, .. } => (url.clone(), Some(*size as u64)),
+            LessonAsset::Embed { url, .. } => (url.clone(), None),
+        };
+
+        AssetState {
+            source,
+            file_name: self.file_name.clone(),
+            embed_file_name: None,
+            size,
+            pending: !self.is_stored,
+            checksum: self.checksum.clone(),
+            validators: self.validators.clone(),
+        }
+    }
+
+    /// Determine whether the asset is stored already.
+    ///
+    /// In mirror mode, existing files are skipped, unless the asset changed since the previous run,
+    /// or its download did not complete.
+    fn update_stored(&mut self, context: &Context) {
+        let asset_state = self.asset_state();
+        let previous_asset_state = context
+            .previous_state()
+            .items
+            .get(&self.lesson.id)
+            .and_then(|previous| {
+                previous
+                    .assets
+                    .iter()
+                    .find(|previous_asset| previous_asset.key() == asset_state.key())
+                    .cloned()
+            });
+        let is_changed = previous_asset_state.as_ref().is_some_and(|previous_asset| {
+            previous_asset.pending || !asset_state.is_unchanged(previous_asset)
+        });
+
+        self.is_stored = context.mirror
+            && !is_changed
+            && match self.path() {
+                Some(path) => path.exists(),
+                None => previous_asset_state.is_some(),
+            };
+        if let Some(previous_asset) = previous_asset_state.as_ref().filter(|_| self.is_stored) {
+            self.checksum = previous_asset.checksum.clone();
+            self.validators = previous_asset.validators.clone();
+        }
+
+        if context.mirror && previous_asset_state.is_none() {
+            self.adopt_renamed_file(&asset_state, context);
+        }
+    }
+
+    /// Move a file which the previous run stored under a different name, such as before its extension was corrected,
+    /// into place instead of downloading it again.
+    fn adopt_renamed_file(&mut self, asset_state: &AssetState, context: &Context) {
+        let Some(path) = self.path().filter(|path| !path.exists()) else {
+            return;
+        };
+        let Some(previous_asset) = context
+            .previous_state()
+            .items
+            .get(&self.lesson.id)
+            .and_then(|previous| {
+                previous
+                    .assets
+                    .iter()
+                    .find(|previous_asset| {
+                        !previous_asset.pending && asset_state.is_unchanged(previous_asset)
+                    })
+                    .cloned()
+            })
+        else {
+            return;
+        };
+        let Some(previous_path) = previous_asset
+            .file_name
+            .as_ref()
+            .map(|file_name| self.lesson.path.join(file_name))
+            .filter(|previous_path| previous_path.exists())
+        else {
+            return;
+        };
+
+        match std::fs::rename(&previous_path, &path) {
+            Ok(()) => {
+                info!(
+                    "Renamed '{}' to '{}'.",
+                    previous_path.display(),
+                    path.display()
+                );
+                self.is_stored = true;
+                self.checksum = previous_asset.checksum;
+                self.validators = previous_asset.validators;
+            }
+            Err(error) => warn!(
+                "Failed to rename '{}' to '{}': {error}",
+                previous_path.display(),
+                path.display()
+            ),
+        }
+    }
+
+    /// Create a lazy future downloading the asset, or skipping it if it is stored already.
+    fn into_future(self, context: Arc<Context>) -> BoxFuture<'static, Result<()>> {
+        let path = self.path();
+        let PlannedDownload {
+            lesson,
+            index,
+            asset,
+            vars,
+            is_stored,
+            checksum,
+            validators,
+            ..
+        } = self;
+
+        // Wistia records the size of video renditions, to verify downloads against.
+        let expected_size = match &asset {
+            LessonAsset::Video { size, .. } => Some(*size as u64),
+            _ => None,
+        };
+
+        match asset {
+            LessonAsset::File {
+                url, updated_at, ..
+            }
+            | LessonAsset::Video {
+                url, updated_at, ..
+            } => {
+                let path = path.expect("a file name was resolved for each file and video");
+                let download_path = path.clone();
+                let download_context = context.clone();
+                let download = async move {
+                    // Files downloaded before, to another lesson or course, are linked instead.
+                    let linked = match &context.deduplicator {
+                        Some(deduplicator) if !is_stored => {
+                            deduplicator.link_by_source(&url, &path).await
+                        }
+                        _ => None,
+                    };
+                    let is_linked = linked.is_some();
+
+                    let downloaded = match (is_stored, &validators) {
+                        (false, _) if is_linked => linked,
+                        (false, _) => {
+                            download(&url, &path, expected_size, updated_at, None, &context).await?
+                        }
+                        // Stored files are checked for updates in place, if the server can tell whether they changed.
+                        (true, Some(validators)) => {
+                            let downloaded = download(
+                                &url,
+                                &path,
+                                expected_size,
+                                updated_at,
+                                Some(validators),
+                                &context,
+                            )
+                            .await?;
+                            if downloaded.is_some() {
+                                context.asset_updated(lesson.id, index);
+                            }
+                            downloaded
+                        }
+                        (true, None) => None,
+                    };
+
+                    if let (Some(deduplicator), Some(downloaded)) =
+                        (&context.deduplicator, &downloaded)
+                    {
+                        // Identical files downloaded from different sources are linked as well.
+                        if !is_linked {
+                            deduplicator
+                                .link_by_content(&path, &downloaded.checksum)
+                                .await;
+                        }
+                        deduplicator.insert(&url, &path, downloaded);
+                    }
+
+                    let (checksum, validators) = match downloaded {
+                        Some(downloaded) => (downloaded.checksum, downloaded.validators),
+                        None => {
+                            info!(
+                                "Skipping '{}', which was downloaded before.",
+                                path.display()
+                            );
+                            // Files stored before checksums were recorded are hashed once.
+                            let checksum = match checksum {
+                                Some(checksum) => checksum,
+                                None => integrity::hash_file(&path).await.wrap_err_with(|| {
+                                    format!("Failed to hash '{}'", path.display())
+                                })?,
+                            };
+                            (checksum, validators)
+                        }
+                    };
+
+                    if let Some(source_metadata) = context.source_metadata {
+                        let source = Source {
+                            url: without_query(&url),
+                            lesson_id: lesson.id,
+                            course_id: context.course_id,
+                        };
+                        metadata::write(&path, &source, source_metadata).await;
+                    }
+
+                    context.asset_completed(lesson.id, index, Some(checksum), validators);
+                    Ok(())
+                };
+                skip_length_mismatch(download, download_path, download_context).boxed()
+            }
+            LessonAsset::Embed { url, args } => async move {
+                let files =
+                    download_embed(&url, &args, lesson.clone(), vars, context.clone()).await?;
+
+                if let Some(source_metadata) = context.source_metadata {
+                    let source = Source {
+                        url: without_query(&url),
+                        lesson_id: lesson.id,
+                        course_id: context.course_id,
+                    };
+                    for file in &files {
+                        metadata::write(file, &source, source_metadata).await;
+                    }
+                }
+
+                // The video is verified along with the other files.
+                let file = files.last().and_then(|file| file.file_name());
+                let checksum = match file {
+                    Some(file) => integrity::hash_file(&lesson.path.join(file))
+                        .await
+                        .inspect_err(|error| {
+                            warn!("Failed to hash '{}': {error}", file.to_string_lossy())
+                        })
+                        .ok(),
+                    None => None,
+                };
+                context.embed_completed(
+                    lesson.id,
+                    index,
+                    file.map(|file| file.to_string_lossy().into_owned()),
+                    checksum,
+                );
+                Ok(())
+            }
+            .boxed(),
+        }
+    }
+}
+
+/// Fail only the asset of a download which is not as long as expected, rather than the whole run.
+///
+/// The asset is left pending, so that it is downloaded again by the next run.
+async fn skip_length_mismatch(
+    download: impl Future<Output = Result<()>>,
+    path: PathBuf,
+    context: Arc<Context>,
+) -> Result<()> {
+    match download.await {
+        Err(report) if report.downcast_ref::<LengthMismatch>().is_some() => {
+            warn!("{report}. Skipping '{}'.", path.display());
+            context.summary.failed_download(path, report.to_string());
+            Ok(())
+        }
+        result => result,
+    }
 }
 
 /// Recursively resolve the flat stack of lessons list items into a tree structure by matching the items' `parent_id` propertys.
@@ -148,6 +1018,13 @@ fn resolve_module_tree(
 
     // Recurse, extracting matching module tree child items from the stack and pushing items onto the tree.
     for item in tree_level_items {
+        // Only categories contain items. Items whose `parent_id` points to a lesson stay on the stack,
+        // so that they are collected as orphans.
+        if !item.is_category {
+            tree_level.push(ModuleTreeItem::Lesson { item });
+            continue;
+        }
+
         // Ownership note:
         //
         // `remaining_stack` moves into the recursed function.
@@ -156,18 +1033,7 @@ fn resolve_module_tree(
         let (children, remaining) = resolve_module_tree(Some(item.id), remaining_stack);
         remaining_stack = remaining;
 
-        // Assuming the observation that items with `is_category == true` do not have other items' `parent_id`s pointing to them.
-        // If that turns out to be untrue, then lessons (as opposed to categories) in fact can have children.
-        tree_level.push({
-            if item.is_category {
-                ModuleTreeItem::Category { item, children }
-            } else {
-                if !children.is_empty() {
-                    error!("Collected children for a tree item which is not a category! Children: {children:#?}, Tree item: {item:#?}");
-                }
-                ModuleTreeItem::Lesson { item }
-            }
-        })
+        tree_level.push(ModuleTreeItem::Category { item, children });
     }
 
     // Sort by `position` property.
@@ -185,6 +1051,99 @@ fn resolve_module_tree(
     (tree_level, remaining_stack)
 }
 
+/// Resolve the items left over by `resolve_module_tree` into a module tree of their own.
+///
+/// Items whose `parent_id` points to a missing item or to a lesson become root items of the orphan tree.
+/// If every remaining item's parent is a remaining category, then the items' `parent_id`s form a cycle.
+/// Cycles are reported and broken up at their lowest ID, which then becomes a root item.
+#[instrument(level = Level::DEBUG, skip(summary))]
+fn resolve_orphans(mut stack: Vec<LessonsListItem>, summary: &Summary) -> Vec<ModuleTreeItem> {
+    let mut orphan_tree = Vec::new();
+
+    while !stack.is_empty() {
+        let category_ids: HashSet<Id> = stack
+            .iter()
+            .filter(|item| item.is_category)
+            .map(|item| item.id)
+            .collect();
+
+        let (mut roots, mut remaining_stack): (Vec<_>, Vec<_>) =
+            stack.into_iter().partition(|item| {
+                !item
+                    .parent_id
+                    .is_some_and(|parent_id| category_ids.contains(&parent_id))
+            });
+
+        if roots.is_empty() {
+            let cycle = find_parent_cycle(&remaining_stack);
+            error!("Detected a `parent_id` cycle in the lessons list: {cycle:?}");
+
+            let breaking_id = *cycle
+                .iter()
+                .min()
+                .expect("a parent cycle contains at least one item");
+            summary.parent_cycle(cycle);
+
+            (roots, remaining_stack) = remaining_stack
+                .into_iter()
+                .partition(|item| item.id == breaking_id);
+        }
+
+        stack = remaining_stack;
+
+        for item in roots {
+            orphan_tree.push(if item.is_category {
+                let (children, remaining) = resolve_module_tree(Some(item.id), stack);
+                stack = remaining;
+                ModuleTreeItem::Category { item, children }
+            } else {
+                ModuleTreeItem::Lesson { item }
+            });
+        }
+    }
+
+    // Record all orphaned lessons, including those nested in orphaned categories.
+    fn record_lessons(tree: &[ModuleTreeItem], summary: &Summary) {
+        for tree_item in tree {
+            match tree_item {
+                ModuleTreeItem::Category { children, .. } => record_lessons(children, summary),
+                ModuleTreeItem::Lesson { item } => summary.orphaned_lesson(item.id, &item.name),
+            }
+        }
+    }
+
+    orphan_tree.sort_by_key(|tree_item| match tree_item {
+        ModuleTreeItem::Category { item, .. } => item.position,
+        ModuleTreeItem::Lesson { item } => item.position,
+    });
+    record_lessons(&orphan_tree, summary);
+
+    orphan_tree
+}
+
+/// Follow `parent_id` references from the first item until an ID repeats, returning the cycle in parent order.
+///
+/// Every item on the stack must have a parent on the stack.
+fn find_parent_cycle(stack: &[LessonsListItem]) -> Vec<Id> {
+    let parents: HashMap<Id, Id> = stack
+        .iter()
+        .filter_map(|item| Some((item.id, item.parent_id?)))
+        .collect();
+
+    let mut visited = Vec::new();
+    let mut current = stack[0].id;
+    while !visited.contains(&current) {
+        visited.push(current);
+        current = parents[&current];
+    }
+
+    let cycle_start = visited
+        .iter()
+        .position(|id| *id == current)
+        .expect("loop ends on a visited ID");
+    visited.split_off(cycle_start)
+}
+
 /// Normalize the module tree:
 /// If an empty root category is directly followed by root lessons, then move these lessons into the empty category.
 #[instrument(level = Level::DEBUG)]
@@ -245,20 +1204,44 @@ fn normalize_module_tree(module_tree: Vec<ModuleTreeItem>) -> Vec<ModuleTreeItem
     normalized_tree
 }
 
-/// Recursively process the module tree, traversing all categories' children and fetching all lesson assets.
+/// Recursively process the module tree, traversing all categories' children and discovering all lesson assets.
 #[async_recursion]
 async fn process_tree_recursive(
     module_tree: Vec<ModuleTreeItem>,
-    base_path: Arc<PathBuf>,
-    course_id: Id,
-    authenticated_client: Client,
-    yt_dlp_bin: Arc<PathBuf>,
-) -> Result<BoxStream<'static, BoxFuture<'static, Result<()>>>> {
-    let mut process_tree_stream = stream::iter(module_tree.into_iter().enumerate())
-        .then(move |(index, tree_item)| {
-            let authenticated_client = authenticated_client.clone();
-            let base_path = base_path.clone();
-            let yt_dlp_bin = yt_dlp_bin.clone();
+    level: Arc<TreeLevel>,
+    context: Arc<Context>,
+) -> Result<Vec<LessonAssets>> {
+    // Pad indices to fit the number of siblings, so that directories sort by index.
+    let width = index_width(module_tree.len());
+
+    // Render all sibling directory names up front,
+    // so that names which only differ in characters removed by sanitizing do not end up in the same directory.
+    let names = module_tree
+        .iter()
+        .enumerate()
+        .map(|(index, tree_item)| {
+            let (template, item) = match tree_item {
+                ModuleTreeItem::Category { item, .. } => (&context.templates.category, item),
+                ModuleTreeItem::Lesson { item } => (&context.templates.lesson, item),
+            };
+            let vars = level
+                .vars
+                .clone()
+                .text(Variable::Name, &item.name)
+                .number(Variable::Index, index + 1, width)
+                .number(Variable::Position, item.position, 0)
+                .number(Variable::Id, item.id, 0)
+                .number(Variable::Depth, level.depth, 0);
+
+            template.render(&vars, &context.sanitizer)
+        })
+        .collect();
+    let names = context.sanitizer.disambiguate_siblings(names);
+
+    let mut process_tree_stream = stream::iter(module_tree.into_iter().zip(names))
+        .then(move |(tree_item, name)| {
+            let level = level.clone();
+            let context = context.clone();
 
             async move {
                 match tree_item {
@@ -269,11 +1252,8 @@ async fn process_tree_recursive(
                         info!("Processing category ID '{}'...", category.id);
 
                         // Create a category directory, then recurse into children.
-                        let path = base_path.join(format!(
-                            "{:0>2} {}",
-                            index + 1,
-                            safe_path(&category.name)
-                        ));
+                        let path = level.path.join(name);
+                        place_item(&category, &path, &context).await?;
 
                         info!("Creating category path '{}'.", path.display());
                         create_dir_all(&path)
@@ -282,10 +1262,12 @@ async fn process_tree_recursive(
 
                         process_tree_recursive(
                             children,
-                            Arc::new(path),
-                            course_id,
-                            authenticated_client,
-                            yt_dlp_bin,
+                            Arc::new(TreeLevel {
+                                path,
+                                depth: level.depth + 1,
+                                vars: level.vars.clone().text(Variable::Parent, &category.name),
+                            }),
+                            context,
                         )
                         .await
                     }
@@ -301,11 +1283,9 @@ async fn process_tree_recursive(
                         info!("Processing {log_fmt}");
 
                         // Create a path in which the lesson's downloadable assets will be stored, then fetch content blocks and extract assets.
-                        let path = base_path.join(format!(
-                            "{:0>2} {}",
-                            index + 1,
-                            safe_path(&lesson.name)
-                        ));
+                        let path = level.path.join(name);
+                        place_item(&lesson, &path, &context).await?;
+
                         info!("Creating lesson path '{}'.", path.display());
                         create_dir_all(&path)
                             .await
@@ -313,49 +1293,239 @@ async fn process_tree_recursive(
 
                         // Fetch the lesson's nested content blocks structure.
                         let content_blocks = fetch_lesson_content_blocks(
-                            authenticated_client,
-                            course_id,
+                            context.authenticated_client.clone(),
+                            &context.throttle,
+                            context.course_id,
                             lesson.id,
                             lesson
                                 .content_page_id
                                 .ok_or_else(|| eyre!("Lesson had no content page ID"))?,
                         )
-                        .await?; // TODO: Can we lazily fetch lessons, driven by downloads stream buffering?
+                        .await?; // TODO: Can we lazily fetch lessons, driven by the download queue?
 
-                        // Create a stream of download futures from the lesson's content blocks structure.
+                        // Collect the assets from the lesson's content blocks structure.
                         // Downloadable assets can either be linked to content blocks directly as "goods",
-                        // or found as embedded iframes in lesson HTML content.
-                        let stream = download_content_block_assets_recursive(
-                            content_blocks,
-                            Arc::new(path),
-                            yt_dlp_bin,
-                        );
+                        // or found as embedded iframes and images in lesson HTML content.
+                        let assets =
+                            collect_lesson_assets(content_blocks, lesson.id, &context).await;
 
                         info!("Finished processing {log_fmt}");
 
-                        Ok::<_, Report>(stream)
+                        Ok::<_, Report>(vec![LessonAssets {
+                            lesson: Arc::new(LessonDir {
+                                id: lesson.id,
+                                path,
+                                vars: level
+                                    .vars
+                                    .clone()
+                                    .text(Variable::Lesson, &lesson.name)
+                                    .number(Variable::LessonId, lesson.id, 0),
+                            }),
+                            assets,
+                        }])
                     }
                 }
             }
         })
         .boxed();
 
-    let mut downloads_stream = stream::iter(Vec::new()).boxed();
+    let mut lessons = Vec::new();
 
-    // Handle the result per item (shortcut the discovery stream on error) and then flatten the nested stream.
-    // TODO: Here we are driving the tree assets discovery stream, thus downloading lesson details eagerly. Can we do that lazily instead?
-    while let Some(next_stream) = process_tree_stream.try_next().await? {
-        downloads_stream = downloads_stream.chain(next_stream).boxed();
+    // Handle the result per item (shortcut the discovery stream on error) and then flatten the nested lists.
+    while let Some(next_lessons) = process_tree_stream.try_next().await? {
+        lessons.extend(next_lessons);
     }
 
-    Ok(downloads_stream.boxed())
+    Ok(lessons)
+}
+
+/// Record the path of a category or lesson in the course state.
+///
+/// In mirror mode, if the previous run stored the item at a different path, such as after the item was renamed
+/// or reordered by the seller, then the existing directory is moved to the new path rather than downloaded again.
+#[instrument(level = Level::DEBUG, skip(context))]
+async fn place_item(item: &LessonsListItem, path: &Path, context: &Context) -> Result<()> {
+    let relative_path = path
+        .strip_prefix(&context.course_path)
+        .wrap_err("Item path must be within the course path")?
+        .to_owned();
+
+    // The fallback category of orphaned items is recorded apart from the course's items.
+    let previous_relative_path = if item.is_unsorted {
+        context.previous_state().unsorted.clone()
+    } else {
+        context
+            .previous_state()
+            .items
+            .get(&item.id)
+            .map(|previous| previous.path.clone())
+    };
+
+    if context.mirror {
+        if let Some(previous_relative_path) = previous_relative_path {
+            let previous_path = context.course_path.join(&previous_relative_path);
+
+            if previous_relative_path != relative_path && previous_path.is_dir() {
+                if path.exists() {
+                    warn!(
+                        "Cannot move '{}' to '{}', as the target path already exists.",
+                        previous_path.display(),
+                        path.display()
+                    );
+                } else {
+                    info!(
+                        "Moving '{}' to '{}'...",
+                        previous_path.display(),
+                        path.display()
+                    );
+                    if let Some(parent) = path.parent() {
+                        create_dir_all(parent)
+                            .await
+                            .wrap_err("Failed to create parent path")?;
+                    }
+                    tokio::fs::rename(&previous_path, path)
+                        .await
+                        .wrap_err("Failed to move renamed item")?;
+
+                    context
+                        .previous_state()
+                        .relocate(&previous_relative_path, &relative_path);
+                    context.summary.moved_item(previous_path, path.to_owned());
+                }
+            }
+        }
+    }
+
+    if item.is_unsorted {
+        context.state().unsorted = Some(relative_path);
+    } else {
+        context.state().items.insert(
+            item.id,
+            ItemState {
+                name: item.name.clone(),
+                is_category: item.is_category,
+                path: relative_path,
+                assets: Vec::new(),
+            },
+        );
+    }
+
+    Ok(())
+}
+
+/// How to treat local lessons and categories which were removed from the course.
+#[derive(Clone, Copy, Debug)]
+enum RemovedItems {
+    Keep,
+    Archive,
+    Delete,
+}
+
+/// Handle local lessons and categories which were recorded by the previous run, but are no longer part of the course.
+#[instrument(level = Level::DEBUG, skip(context))]
+async fn remove_missing_items(context: &Context, removed_items: RemovedItems) -> Result<()> {
+    let previous_state = context.previous_state().clone();
+    let state = context.state().clone();
+
+    // An empty or truncated lessons list would otherwise wipe the offline cache, possibly unattended in watch mode.
+    let missing = previous_state
+        .items
+        .keys()
+        .filter(|id| !state.items.contains_key(id))
+        .count();
+    let removed_items = match removed_items {
+        RemovedItems::Delete
+            if is_mass_removal(previous_state.items.len(), state.items.len(), missing) =>
+        {
+            warn!(
+                "{missing} of {} lessons and categories are missing from the course, which looks like an incomplete lessons list. Not deleting any of them. Delete them manually if they were removed indeed.",
+                previous_state.items.len()
+            );
+            RemovedItems::Keep
+        }
+        removed_items => removed_items,
+    };
+
+    for (id, previous) in &previous_state.items {
+        if state.items.contains_key(id) {
+            continue;
+        }
+
+        let path = context.course_path.join(&previous.path);
+        // Never remove a directory which (now) holds items of the current course structure.
+        if !path.is_dir()
+            || state
+                .items
+                .values()
+                .map(|item| &item.path)
+                .chain(&state.unsorted)
+                .any(|item_path| item_path.starts_with(&previous.path))
+        {
+            continue;
+        }
+
+        match removed_items {
+            RemovedItems::Keep => {
+                warn!(
+                    "'{}' was removed from the course. Pass `--delete` or `--archive` to remove it locally.",
+                    path.display()
+                );
+            }
+            RemovedItems::Archive => {
+                let archive_path = context
+                    .course_path
+                    .join(ARCHIVE_DIR_NAME)
+                    .join(&previous.path);
+                info!(
+                    "Archiving removed '{}' to '{}'...",
+                    path.display(),
+                    archive_path.display()
+                );
+                if let Some(parent) = archive_path.parent() {
+                    create_dir_all(parent)
+                        .await
+                        .wrap_err("Failed to create archive path")?;
+                }
+                tokio::fs::rename(&path, &archive_path)
+                    .await
+                    .wrap_err("Failed to archive removed item")?;
+            }
+            RemovedItems::Delete => {
+                info!("Deleting removed '{}'...", path.display());
+                tokio::fs::remove_dir_all(&path)
+                    .await
+                    .wrap_err("Failed to delete removed item")?;
+            }
+        }
+
+        context.summary.removed_item(previous.name.clone(), path);
+    }
+
+    // The fallback category of orphaned items is removed once it is no longer needed, and empty.
+    if let Some(previous_unsorted) = previous_state.unsorted.filter(|_| state.unsorted.is_none()) {
+        let path = context.course_path.join(previous_unsorted);
+        if tokio::fs::remove_dir(&path).await.is_ok() {
+            debug!("Removed the empty '{}'.", path.display());
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether `missing` of the `previous` items are too many to delete: all of them, or more than half.
+fn is_mass_removal(previous: usize, current: usize, missing: usize) -> bool {
+    missing > 0 && (current == 0 || missing * 2 > previous)
 }
 
 /// Fetch a course's metadata.
-#[instrument(level = Level::DEBUG)]
-async fn fetch_course(authenticated_client: Client, course_id: Id) -> Result<Course> {
+#[instrument(level = Level::DEBUG, skip(throttle))]
+async fn fetch_course(
+    authenticated_client: Client,
+    throttle: &Throttle,
+    course_id: Id,
+) -> Result<Course> {
     let url = format!("https://api.elopage.com/v1/payer/course_sessions/{course_id}");
-    let response: CourseResponse = authenticated_client.get(url).send().await?.json().await?;
+    let response: CourseResponse = fetch_api(&authenticated_client, throttle, &url).await?;
 
     debug!("{response:#?}");
 
@@ -363,22 +1533,24 @@ async fn fetch_course(authenticated_client: Client, course_id: Id) -> Result<Cou
 }
 
 /// Fetch the course's lessons list, containing a flat structure of lessons and possibly lesson-parent categories.
-#[instrument(level = Level::DEBUG)]
+#[instrument(level = Level::DEBUG, skip(throttle))]
 async fn fetch_lessons_list(
     authenticated_client: Client,
+    throttle: &Throttle,
     course_id: Id,
 ) -> Result<Vec<LessonsListItem>> {
     let url = format!("https://api.elopage.com/v1/payer/course_sessions/{course_id}/lessons?page=1&query=&per=10000&sort_key=id&sort_dir=desc&course_session_id={course_id}");
-    let response: LessonsListResponse = authenticated_client.get(url).send().await?.json().await?;
+    let response: LessonsListResponse = fetch_api(&authenticated_client, throttle, &url).await?;
 
     debug!("{response:#?}");
 
     Ok(response.data.list)
 }
 
-#[instrument(level = Level::DEBUG)]
+#[instrument(level = Level::DEBUG, skip(throttle))]
 async fn fetch_lesson_content_blocks(
     authenticated_client: Client,
+    throttle: &Throttle,
     course_id: Id,
     lesson_id: Id,
     content_page_id: Id,
@@ -386,7 +1558,7 @@ async fn fetch_lesson_content_blocks(
     let url = format!("https://api.elopage.com/v1/payer/course_sessions/{course_id}/lessons/{lesson_id}/content_pages/{content_page_id}?screen_size=desktop");
     debug!("URL: {url}");
 
-    let response: serde_json::Value = authenticated_client.get(&url).send().await?.json().await?;
+    let response: serde_json::Value = fetch_api(&authenticated_client, throttle, &url).await?;
     debug!("Raw JSON: {response:#?}");
 
     let response: ContentBlocksResponse = serde_json::from_value(response)?;
@@ -395,111 +1567,333 @@ async fn fetch_lesson_content_blocks(
     Ok(response.data.content_blocks)
 }
 
-// TODO: Rename functions like these, which no longer actively download, but rather compile a stream of lazy download futures.
-/// Recurse nested content blocks, discovering and downloading all attached videos and files.
-/// All discovered assets are fed into the same stream, which is returned to the caller.
-#[instrument(level = Level::DEBUG)]
-fn download_content_block_assets_recursive(
+/// Send a request to the elopage API, within its provider's limits, and parse the JSON response.
+async fn fetch_api<T: DeserializeOwned>(
+    authenticated_client: &Client,
+    throttle: &Throttle,
+    url: &str,
+) -> Result<T> {
+    let (response, _permit) = throttle.send(url, || authenticated_client.get(url)).await?;
+
+    Ok(response.json().await?)
+}
+
+/// Collect the assets of a lesson's content blocks, keeping only the linked files which are documents.
+async fn collect_lesson_assets(
     content_blocks: Vec<ContentBlock>,
-    path: Arc<PathBuf>,
-    yt_dlp_bin: Arc<PathBuf>,
-) -> BoxStream<'static, BoxFuture<'static, Result<()>>> {
-    let stream = stream::iter(content_blocks).flat_map(move |content_block| {
-        let path = path.clone();
-        let yt_dlp_bin = yt_dlp_bin.clone();
-
-        // Recurse into nested content blocks, if any,
-        // returning a stream of download futures for all assets discovered in deeper-nested content blocks.
-        // If this content block has no children, then an empty stream will be returned, which is immediately ready to yield `None`.
-        let mut stream = download_content_block_assets_recursive(
-            content_block.children,
-            path.clone(),
-            yt_dlp_bin.clone(),
-        );
+    lesson_id: Id,
+    context: &Context,
+) -> Vec<LessonAsset> {
+    let mut assets = Vec::new();
+    collect_content_block_assets_recursive(content_blocks, context, &mut assets);
+
+    links::retain_documents(assets, lesson_id, context).await
+}
+
+/// Recurse nested content blocks, collecting all attached videos and files, as well as embedded videos, images and linked files.
+/// Assets are collected in order of appearance: assets of nested content blocks first,
+/// then videos, images and files embedded in or linked from the content block's HTML content, then assets attached to the content block.
+#[instrument(level = Level::DEBUG, skip(context, assets))]
+fn collect_content_block_assets_recursive(
+    content_blocks: Vec<ContentBlock>,
+    context: &Context,
+    assets: &mut Vec<LessonAsset>,
+) {
+    for content_block in content_blocks {
+        // Recurse into nested content blocks, if any, collecting all assets discovered in deeper-nested content blocks.
+        collect_content_block_assets_recursive(content_block.children, context, assets);
 
-        // Chain download futures for assets discovered in the content block's HTML content to the stream returned from recursion.
         if let Some(content) = content_block.content.text {
-            // Extract vimeo and youtube embed URLs from this content block's text content.
-            let embed_urls = REGEX_VIDEO_IFRAME
-                .captures_iter(&content)
-                .filter_map(|captures| captures.name("embed_url"))
-                .map(|embed_url_match| htmlize::unescape(embed_url_match.as_str()).into_owned())
-                .collect::<Vec<_>>();
-
-            // Create a new stream of pinned download futures, and chain it to the stream returned from recursion.
-            let path = path.clone();
-            stream = stream
-                .chain(stream::iter(embed_urls).map(move |embed_url| {
-                    let path = path.clone();
-                    let yt_dlp_bin = yt_dlp_bin.clone();
-
-                    async move { download_embed(embed_url, path, yt_dlp_bin).await }.boxed()
-                }))
-                .boxed();
-        }
-
-        // Chain download futures for assets directly attached to the content block to the stream returned from recursion.
-        if let Some(goods) = content_block.goods {
-            stream = stream
-                .chain(stream::iter(goods).flat_map(move |good| {
-                    let good = good.digital;
-
-                    // None or more downloadable assets ("goods") might be directly attached to the content block.
-                    let mut download_futures: Vec<BoxFuture<'static, Result<()>>> = vec![];
-
-                    // Files can be streamed to disk by URL.
-                    if let Some(file) = good.file {
-                        let path = path.clone();
-                        download_futures
-                            .push(async move { download_file(file, path).await }.boxed());
-                    }
+            let html_assets = HtmlAssets::extract(&content, &context.embed_providers);
+
+            // Videos embedded into or linked from this content block's text content, as planned by their provider.
+            assets.extend(html_assets.embeds.into_iter().map(|embed| match embed {
+                EmbedDownload::YtDlp { url, args } => LessonAsset::Embed { url, args },
+                EmbedDownload::File { url } => LessonAsset::File {
+                    name: url_file_name(&url),
+                    url,
+                    linked: false,
+                    updated_at: None,
+                },
+            }));
+
+            // Images, such as diagrams and slides, are downloaded like attached files.
+            if context.images {
+                assets.extend(html_assets.images.into_iter().map(|url| LessonAsset::File {
+                    name: url_file_name(&url),
+                    url,
+                    linked: false,
+                    updated_at: None,
+                }));
+            }
 
-                    // Wistia videos can be streamed to disk after discovering the URL to the largest version of the video.
-                    if let Some(wistia_data) = good.wistia_data {
-                        let path = path.clone();
-                        download_futures
-                            .push(async move { download_video(wistia_data, path).await }.boxed());
+            // Files linked from the text, on allowed hosts. Those which are not documents are dropped later on.
+            if let Some(links) = &context.links {
+                assets.extend(
+                    html_assets
+                        .links
+                        .into_iter()
+                        .filter(|url| links.allows_host(url))
+                        .map(|url| LessonAsset::File {
+                            name: None,
+                            url,
+                            linked: true,
+                            updated_at: None,
+                        }),
+                );
+            }
+        }
+
+        // None or more downloadable assets ("goods") might be directly attached to the content block.
+        for good in content_block.goods.into_iter().flatten() {
+            let good = good.digital;
+            let updated_at = good
+                .updated_at
+                .as_deref()
+                .and_then(metadata::parse_timestamp);
+
+            // Files can be streamed to disk by URL.
+            if let Some(FileAsset {
+                name,
+                original: Some(url),
+            }) = good.file
+            {
+                if url != "https://api.elopage.com/pca/digitals/files/original/missing.png" {
+                    assets.push(LessonAsset::File {
+                        url,
+                        name,
+                        linked: false,
+                        updated_at,
+                    });
+                }
+            }
+
+            // Wistia videos can be streamed to disk after discovering the URL to the largest version of the video.
+            if let Some(wistia_data) = good.wistia_data {
+                if let Some(asset_list) = &wistia_data.assets {
+                    assert!(matches!(wistia_data.r#type.as_deref(), Some("Video")));
+
+                    // Lower renditions are kept, to fit the course into `--max-total-size`.
+                    let mut renditions = asset_list.clone();
+                    renditions.sort_by_key(|asset| Reverse(asset.file_size));
+
+                    // None if assets is empty
+                    if let Some(asset) = renditions.first() {
+                        assets.push(LessonAsset::Video {
+                            url: asset.url.clone(),
+                            name: wistia_data.name.clone(),
+                            size: asset.file_size,
+                            renditions,
+                            updated_at,
+                        });
                     }
+                }
+            }
+        }
+    }
+}
 
-                    stream::iter(download_futures).boxed()
-                }))
-                .boxed();
+/// Resolve the download targets of a lesson's assets.
+///
+/// File names of all natively downloaded assets are resolved up front,
+/// so that assets which would be stored under the same name can be told apart by a ` (2)`, ` (3)`, ... suffix.
+#[instrument(level = Level::DEBUG, skip(context))]
+fn plan_lesson_downloads(
+    assets: Vec<LessonAsset>,
+    lesson: Arc<LessonDir>,
+    context: &Context,
+) -> Result<Vec<PlannedDownload>> {
+    // Number assets in order of appearance, padded to fit the number of assets in the lesson.
+    let width = index_width(assets.len());
+    let asset_vars = (1..=assets.len())
+        .map(|index| lesson.vars.clone().number(Variable::Index, index, width))
+        .collect::<Vec<_>>();
+
+    let mut file_names = Vec::new();
+    for (asset, vars) in assets.iter().zip(&asset_vars) {
+        match asset {
+            LessonAsset::File { url, name, .. } | LessonAsset::Video { url, name, .. } => {
+                let name = asset_name(url, name)?;
+                let (stem, ext) = name.rsplit_once('.').unwrap_or((&name, ""));
+                file_names.push(
+                    context.templates.asset.render(
+                        &vars
+                            .clone()
+                            .text(Variable::Name, &name)
+                            .text(Variable::Stem, stem)
+                            .text(Variable::Ext, ext),
+                        &context.sanitizer.for_files(),
+                    ),
+                );
+            }
+            // yt-dlp determines the file names of embedded videos.
+            LessonAsset::Embed { .. } => {}
         }
+    }
 
-        stream
-    });
+    let mut unique_file_names = context
+        .sanitizer
+        .for_files()
+        .disambiguate_siblings(file_names.clone())
+        .into_iter();
+    for (file_name, unique_file_name) in file_names.iter().zip(unique_file_names.clone()) {
+        if *file_name != unique_file_name {
+            warn!(
+                "Multiple assets of lesson path '{}' are named '{file_name}'. Storing one of them as '{unique_file_name}'.",
+                lesson.path.display()
+            );
+            context.summary.renamed_asset(
+                lesson.path.join(file_name),
+                lesson.path.join(&unique_file_name),
+            );
+        }
+    }
 
-    Box::pin(stream)
+    Ok(assets
+        .into_iter()
+        .zip(asset_vars)
+        .enumerate()
+        .map(|(index, (asset, vars))| {
+            let (file_name, estimated_size) = match &asset {
+                LessonAsset::File { .. } => (unique_file_names.next(), None),
+                LessonAsset::Video { size, .. } => (unique_file_names.next(), Some(*size as u64)),
+                LessonAsset::Embed { .. } => (None, None),
+            };
+
+            PlannedDownload {
+                lesson: lesson.clone(),
+                index,
+                asset,
+                vars,
+                file_name,
+                estimated_size,
+                is_stored: false,
+                checksum: None,
+                validators: None,
+            }
+        })
+        .collect())
 }
 
-/// Download an embedded Vimeo video.
-#[instrument(level = Level::DEBUG)]
+/// Record the lessons' assets in the course state, to report changes and resume downloads on the next run.
+fn record_assets(downloads: &[PlannedDownload], context: &Context) {
+    let mut state = context.state();
+    for download in downloads {
+        if let Some(item) = state.items.get_mut(&download.lesson.id) {
+            item.assets.push(download.asset_state());
+        }
+    }
+}
+
+/// Determine an asset's name from its given name, or from the last segment of its URL.
+fn asset_name(url: &str, name: &Option<String>) -> Result<String> {
+    name.clone()
+        .or_else(|| url_file_name(url))
+        .ok_or_else(|| eyre!("File URL '{url}' had no last path segment"))
+}
+
+/// Name a file after the percent-decoded last path segment of its URL, if it has one.
+fn url_file_name(url: &str) -> Option<String> {
+    let parsed_url: reqwest::Url = url.parse().ok()?;
+    parsed_url
+        .path_segments()?
+        .next_back()
+        .map(extension::percent_decode)
+        .filter(|name| !name.is_empty())
+}
+
+/// Download an embedded video with `yt-dlp`, passing the embed provider's extra `args`.
+///
+/// Returns the paths of the downloaded files, as listed by `yt-dlp`.
+#[instrument(level = Level::DEBUG, skip(context))]
 async fn download_embed(
-    embed_url: impl AsRef<OsStr> + Display + Debug,
-    path: Arc<PathBuf>,
-    yt_dlp_bin: Arc<PathBuf>,
-) -> Result<()> {
+    embed_url: impl AsRef<str> + AsRef<OsStr> + Display + Debug,
+    args: &[String],
+    lesson: Arc<LessonDir>,
+    vars: TemplateVars,
+    context: Arc<Context>,
+) -> Result<Vec<PathBuf>> {
+    let path = &lesson.path;
     info!("Downloading '{}' to '{}'...", embed_url, path.display());
 
-    // Spawn a task handling the child process,
-    // and read piped IO streams into trace logs.
-    child_read_to_end(
-        Command::new(&*yt_dlp_bin)
+    // Let yt-dlp fill in the video title, ID and extension, using its default output template for `{name}`.
+    let output_template = context.templates.asset.render_yt_dlp(
+        &vars
+            .raw(Variable::Name, "%(title)s [%(id)s].%(ext)s")
+            .raw(Variable::Stem, "%(title)s [%(id)s]")
+            .raw(Variable::Ext, "%(ext)s"),
+        &context.sanitizer,
+    );
+
+    let mut command = Command::new(&context.yt_dlp_bin);
+
+    // Keep yt-dlp out of the terminal's process group, so that a first Ctrl-C lets it finish the download.
+    #[cfg(unix)]
+    command.process_group(0);
+
+    let _permit = context.throttle.acquire(embed_url.as_ref()).await;
+
+    // If the download fails or is aborted, yt-dlp is killed, and its leftover fragments are removed.
+    let temp_dir = YtDlpTempDir::create(path).await?;
+    // The list's path is an output template, in which `%` is escaped. Relative paths would be placed below `--paths`.
+    let downloads_list = std::path::absolute(temp_dir.downloads_list())?
+        .to_string_lossy()
+        .replace('%', "%%");
+
+    // yt-dlp limits its own rate to one download's share of the rate limit, which native downloads leave to it.
+    let rate_share = context.rate_limiter.take_share();
+
+    // Detect throttling in yt-dlp's error output.
+    let stderr_context = context.clone();
+    let stderr_embed_url = embed_url.to_string();
+    let on_stderr = move |line: String| {
+        if line.contains("HTTP Error 429") || line.contains("HTTP Error 503") {
+            stderr_context.throttle.throttled(&stderr_embed_url);
+        }
+        warn!(line);
+    };
+
+    // Run the child process, and read piped IO streams into trace logs.
+    let result = child_read_to_end(
+        command
             .kill_on_drop(true)
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
             .arg("--newline")
+            .arg("--mtime")
             .arg("--no-colors")
             .arg("--legacy-server-connect")
             .arg("--add-header")
             .arg("Referer:https://elopage.com/")
             .arg(&embed_url)
             .arg("--paths")
-            .arg(&*path)
+            .arg(path)
+            .arg("--paths")
+            .arg(format!("temp:{}", temp_dir.path().display()))
+            .arg("--output")
+            .arg(output_template)
+            .arg("--print-to-file")
+            .arg("after_move:filepath")
+            .arg(downloads_list)
+            .args(
+                (context.sanitizer.profile() != SanitizeProfile::Posix)
+                    .then_some("--windows-filenames"),
+            )
+            .args((context.source_metadata == Some(SourceMetadata::Xattr)).then_some("--xattrs"))
+            .args(args)
+            .args(
+                rate_share
+                    .iter()
+                    .flat_map(|share| ["--limit-rate".to_owned(), share.rate.to_string()]),
+            )
             .spawn()
             .wrap_err("yt-dlp command failed to start")?,
+        on_stderr,
     )
-    .await?;
+    .await;
+
+    result?;
+    context.throttle.succeeded(embed_url.as_ref());
 
     info!(
         "Finished downloading '{}' to '{}'.",
@@ -507,12 +1901,17 @@ async fn download_embed(
         path.display()
     );
 
-    Ok(())
+    Ok(temp_dir.downloaded_files().await)
 }
 
-/// Spawn a child process and read its stdout and stderr streams to their end.
-#[instrument(level = Level::DEBUG)]
-async fn child_read_to_end(mut child: Child) -> Result<()> {
+/// Read a child process' stdout and stderr streams to their end, and wait for it to exit.
+///
+/// The child is killed if the returned future is dropped.
+#[instrument(level = Level::DEBUG, skip(on_stderr))]
+async fn child_read_to_end(
+    mut child: Child,
+    on_stderr: impl Fn(String) + Send + 'static,
+) -> Result<()> {
     let consume_stdout = child
         .stdout
         .take()
@@ -521,20 +1920,15 @@ async fn child_read_to_end(mut child: Child) -> Result<()> {
     let consume_stderr = child
         .stderr
         .take()
-        .map(|stderr| consume_stream(stderr, |line| warn!(line)));
+        .map(|stderr| consume_stream(stderr, on_stderr));
 
     let await_exit = async {
-        tokio::spawn(async move {
-            child
-                .wait()
-                .await
-                .wrap_err("yt-dlp command failed to run")?;
+        child
+            .wait()
+            .await
+            .wrap_err("yt-dlp command failed to run")?;
 
-            Ok::<(), Report>(())
-        })
-        .await??;
-
-        Ok(())
+        Ok::<(), Report>(())
     };
 
     tokio::try_join!(
@@ -558,10 +1952,10 @@ async fn maybe_join(maybe_spawned: Option<JoinHandle<Result<()>>>) -> Result<()>
 }
 
 /// Consume a child process stream, invoking a callback on each line.
-#[instrument(level = Level::DEBUG)]
+#[instrument(level = Level::DEBUG, skip(callback))]
 fn consume_stream<A: AsyncRead + Unpin + Send + 'static + Debug>(
     reader: A,
-    callback: fn(String),
+    callback: impl Fn(String) + Send + 'static,
 ) -> JoinHandle<Result<()>> {
     let mut lines = BufReader::new(reader).lines();
 
@@ -574,75 +1968,254 @@ fn consume_stream<A: AsyncRead + Unpin + Send + 'static + Debug>(
     })
 }
 
-/// Stream a file asset to disk.
-#[instrument(level = Level::DEBUG)]
-async fn download_file(file: FileAsset, path: Arc<PathBuf>) -> Result<()> {
-    if let Some(original) = &file.original {
-        if original == "https://api.elopage.com/pca/digitals/files/original/missing.png" {
-            return Ok(());
-        }
-
-        download(original, &file.name, &path).await?;
+/// Stream a video or file to disk, within the rate limit and the provider's concurrency limit.
+///
+/// With adaptive throttling, downloads which are throttled by the provider are retried.
+/// The download's length is verified against the `Content-Length` and the `expected_size`, before it is moved into place.
+/// Its modification time is set from the `Last-Modified` header, or else from the time elopage recorded, `updated_at`.
+///
+/// With the `validators` of a stored file, the file is only downloaded again if the server reports it changed.
+/// `None` is returned if it did not.
+#[instrument(level = Level::DEBUG, skip(context))]
+async fn download(
+    url: &str,
+    path: &Path,
+    expected_size: Option<u64>,
+    updated_at: Option<SystemTime>,
+    validators: Option<&Validators>,
+    context: &Context,
+) -> Result<Option<Downloaded>> {
+    match validators {
+        Some(_) => debug!("Checking '{}' for updates...", path.display()),
+        None => info!("Downloading '{}' to '{}'...", url, path.display()),
     }
 
-    Ok(())
-}
+    let client = Client::new();
 
-/// Stream a video to disk.
-#[instrument(level = Level::DEBUG)]
-async fn download_video(wistia_data: WistiaData, path: Arc<PathBuf>) -> Result<()> {
-    if let Some(assets) = &wistia_data.assets {
-        assert!(matches!(wistia_data.r#type.as_deref(), Some("Video")));
+    let (response, permit) = context
+        .throttle
+        .send(url, || {
+            let mut request = client.get(url);
+            if let Some(validators) = validators {
+                if let Some(etag) = &validators.etag {
+                    request = request.header(IF_NONE_MATCH, etag);
+                }
+                if let Some(last_modified) = &validators.last_modified {
+                    request = request.header(IF_MODIFIED_SINCE, last_modified);
+                }
+            }
+            request
+        })
+        .await?;
 
-        let largest_asset = assets.iter().max_by_key(|asset| asset.file_size);
-        // None if assets is empty
-        if let Some(asset) = largest_asset {
-            download(&asset.url, &wistia_data.name, &path).await?;
-        }
-    }
+    let header = |name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_owned)
+    };
+    let new_validators = Validators {
+        etag: header(ETAG),
+        last_modified: header(LAST_MODIFIED),
+    };
 
-    Ok(())
-}
+    // Some servers ignore conditional requests, but serve the same validators for an unchanged file.
+    if response.status() == StatusCode::NOT_MODIFIED
+        || validators.is_some_and(|validators| validators.matches(&new_validators))
+    {
+        return Ok(None);
+    }
+    if validators.is_some() {
+        info!(
+            "'{}' was updated on the server. Downloading '{}' again...",
+            path.display(),
+            url
+        );
+    }
 
-/// Stream a video or file to disk.
-#[instrument(level = Level::DEBUG)]
-async fn download(url: &str, name: &Option<String>, path: &Path) -> Result<()> {
-    let parsed_url: reqwest::Url = url.parse()?;
-    let name = match name {
-        Some(name) => name,
-        None => parsed_url
-            .path_segments()
-            .ok_or_else(|| eyre!("File URL had no path segments"))?
-            .last()
-            .ok_or_else(|| eyre!("File URL had no last path segment"))?,
+    let mut file = PartialFile::create(path).await?;
+
+    // Download large files over multiple connections, if the server serves byte ranges.
+    // Each connection beyond the first takes another of the provider's free slots.
+    let mut range_permits = Vec::new();
+    if context.connections > 1
+        && response
+            .content_length()
+            .is_some_and(|length| length >= context.chunked_min_size)
+        && ranges::accepts_ranges(&response)
+    {
+        range_permits
+            .extend((1..context.connections).map_while(|_| context.throttle.try_acquire(url)));
+    }
+    let chunked_length = response
+        .content_length()
+        .filter(|_| !range_permits.is_empty());
+
+    let content_length = response.content_length();
+    let last_modified = new_validators
+        .last_modified
+        .as_deref()
+        .and_then(metadata::parse_http_date);
+    let checksum = match chunked_length {
+        Some(length) => {
+            // The ranges are requested separately.
+            drop(response);
+            let connections = range_permits.len() + 1;
+            debug!("Downloading '{url}' over {connections} connections.");
+            let served = ranges::download_ranges(
+                &client,
+                &context.throttle,
+                url,
+                &mut file,
+                length,
+                connections,
+                &context.rate_limiter,
+            )
+            .await?;
+            drop(range_permits);
+
+            if served {
+                // The ranges arrive out of order, so the assembled file is hashed.
+                integrity::hash_file(file.partial_path()).await?
+            } else {
+                debug!("Server did not serve ranges of '{url}', downloading it over a single connection.");
+                file.file().set_len(0).await?;
+                // The slot is taken again, so that a provider limited to one connection does not wait for itself.
+                drop(permit);
+                let (response, _permit) = context.throttle.send(url, || client.get(url)).await?;
+                stream_to_file(response, &mut file, context).await?
+            }
+        }
+        None => stream_to_file(response, &mut file, context).await?,
     };
-    let path = path.join(safe_path(name));
+    integrity::verify_length(url, &checksum, content_length, expected_size)?;
+    if let Some(modified) = last_modified.or(updated_at) {
+        file.set_modified(modified).await?;
+    }
 
-    info!("Downloading '{}' to '{}'...", url, path.display());
+    file.persist().await?;
 
-    let response = reqwest::get(url).await?;
+    info!("Finished downloading '{}' to '{}'.", url, path.display());
 
-    let mut file = File::create(&path).await?;
+    Ok(Some(Downloaded {
+        checksum,
+        validators: (new_validators.etag.is_some() || new_validators.last_modified.is_some())
+            .then_some(new_validators),
+    }))
+}
 
+/// Stream a response's body to a partial file, within the rate limit, hashing it on the way.
+async fn stream_to_file(
+    response: Response,
+    file: &mut PartialFile,
+    context: &Context,
+) -> Result<Checksum> {
+    let mut hasher = StreamHasher::default();
     let mut stream = response.bytes_stream();
     while let Some(chunk) = stream.next().await {
-        tokio::io::copy(&mut chunk?.as_ref(), &mut file).await?;
+        let chunk = chunk?;
+        context.rate_limiter.consume(chunk.len()).await;
+        hasher.update(&chunk);
+        tokio::io::copy(&mut chunk.as_ref(), file.file()).await?;
     }
 
-    info!("Finished downloading '{}' to '{}'.", url, path.display());
+    Ok(hasher.finish())
+}
 
-    Ok(())
+/// A completed download of a video or file.
+#[derive(Debug)]
+struct Downloaded {
+    checksum: Checksum,
+    /// HTTP validators the file was served with, if any.
+    validators: Option<Validators>,
 }
 
-/// Replace some non path-safe characters for wider file-system compatibility (e.g. with ExFAT).
-#[instrument(level = Level::DEBUG)]
-fn safe_path(s: impl AsRef<str> + Debug) -> String {
-    htmlize::unescape(s.as_ref())
-        .replace(": ", " - ")
-        .replace(" / ", " - ")
-        .replace('/', " - ")
-        .replace('*', "-")
-        .replace(['?', '"', ':'], "")
-        .trim()
-        .to_owned()
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(id: Id, is_category: bool, parent_id: Option<Id>) -> LessonsListItem {
+        LessonsListItem {
+            id,
+            name: format!("Item {id}"),
+            active: true,
+            content_page_id: (!is_category).then_some(id),
+            is_category,
+            parent_id,
+            position: id,
+            is_unsorted: false,
+        }
+    }
+
+    /// The tree's item IDs, such as `1(2 3) 4`, sorted by ID.
+    fn outline(tree: &[ModuleTreeItem]) -> String {
+        let mut items: Vec<_> = tree
+            .iter()
+            .map(|tree_item| match tree_item {
+                ModuleTreeItem::Category { item, children } => {
+                    (item.id, format!("{}({})", item.id, outline(children)))
+                }
+                ModuleTreeItem::Lesson { item } => (item.id, item.id.to_string()),
+            })
+            .collect();
+        items.sort();
+
+        items
+            .into_iter()
+            .map(|(_, outline)| outline)
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    #[test]
+    fn collects_children_of_lessons_as_orphans() {
+        let stack = vec![
+            item(1, true, None),
+            item(2, false, Some(1)),
+            // Children of a lesson, including a category with a lesson of its own.
+            item(3, false, Some(2)),
+            item(4, true, Some(2)),
+            item(5, false, Some(4)),
+            // A missing parent.
+            item(6, false, Some(99)),
+        ];
+
+        let (tree, remaining_stack) = resolve_module_tree(None, stack);
+        assert_eq!(outline(&tree), "1(2)");
+
+        let mut remaining_ids: Vec<_> = remaining_stack.iter().map(|item| item.id).collect();
+        remaining_ids.sort();
+        assert_eq!(remaining_ids, vec![3, 4, 5, 6]);
+
+        let orphan_tree = resolve_orphans(remaining_stack, &Summary::default());
+        assert_eq!(outline(&orphan_tree), "3 4(5) 6");
+    }
+
+    #[test]
+    fn refuses_mass_removals() {
+        assert!(!is_mass_removal(10, 10, 0));
+        assert!(!is_mass_removal(10, 9, 1));
+        assert!(!is_mass_removal(10, 5, 5));
+        assert!(is_mass_removal(10, 4, 6));
+        assert!(is_mass_removal(10, 0, 10));
+        assert!(is_mass_removal(1, 0, 1));
+        assert!(!is_mass_removal(0, 0, 0));
+    }
+
+    #[test]
+    fn breaks_parent_cycles() {
+        let stack = vec![
+            item(1, false, None),
+            item(2, true, Some(3)),
+            item(3, true, Some(2)),
+            item(4, false, Some(3)),
+        ];
+
+        let (tree, remaining_stack) = resolve_module_tree(None, stack);
+        assert_eq!(outline(&tree), "1");
+
+        let orphan_tree = resolve_orphans(remaining_stack, &Summary::default());
+        assert_eq!(outline(&orphan_tree), "2(3(4))");
+    }
 }
@@ -0,0 +1,171 @@
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use clap::ValueEnum;
+use color_eyre::{eyre::WrapErr, Result};
+use serde::Serialize;
+use tracing::{instrument, warn, Level};
+
+use crate::Id;
+
+/// Extension appended to the name of a file to name its sidecar metadata file.
+const SIDECAR_EXTENSION: &str = "source.json";
+
+/// Where to store the source of each downloaded file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub(crate) enum SourceMetadata {
+    /// `user.*` extended attributes of the file, on file systems which support them.
+    Xattr,
+    /// A `<file>.source.json` file next to the file.
+    Sidecar,
+}
+
+/// The origin of a downloaded file.
+#[derive(Debug, Serialize)]
+pub(crate) struct Source<'a> {
+    /// Download URL, without the query string, which might carry an expiring signature.
+    pub url: &'a str,
+    pub lesson_id: Id,
+    pub course_id: Id,
+}
+
+/// Parse an HTTP date, such as a `Last-Modified` header.
+pub(crate) fn parse_http_date(s: &str) -> Option<SystemTime> {
+    httpdate::parse_http_date(s.trim()).ok()
+}
+
+/// Parse an API timestamp, such as `2024-05-01T12:34:56.000Z` or `2024-05-01T14:34:56.000+02:00`.
+pub(crate) fn parse_timestamp(s: &str) -> Option<SystemTime> {
+    let s = s.trim();
+
+    // humantime only understands UTC, so offsets are applied by hand.
+    let (time, offset) = match s
+        .len()
+        .checked_sub(6)
+        .filter(|&split| s.is_char_boundary(split))
+        .map(|split| s.split_at(split))
+    {
+        Some((time, offset))
+            if (offset.starts_with('+') || offset.starts_with('-'))
+                && offset.as_bytes()[3] == b':' =>
+        {
+            (time, Some(offset))
+        }
+        _ => (s, None),
+    };
+    let time = humantime::parse_rfc3339_weak(time.trim_end_matches('Z')).ok()?;
+
+    let Some(offset) = offset else {
+        return Some(time);
+    };
+    let hours: u64 = offset[1..3].parse().ok()?;
+    let minutes: u64 = offset[4..6].parse().ok()?;
+    let offset_duration = Duration::from_secs(hours * 60 * 60 + minutes * 60);
+    if offset.starts_with('+') {
+        time.checked_sub(offset_duration)
+    } else {
+        time.checked_add(offset_duration)
+    }
+}
+
+/// Record the source of a downloaded file.
+///
+/// Failing to write the metadata, such as on file systems without extended attributes, is logged, but not fatal.
+#[instrument(level = Level::DEBUG)]
+pub(crate) async fn write(path: &Path, source: &Source<'_>, kind: SourceMetadata) {
+    let result = match kind {
+        SourceMetadata::Xattr => write_xattrs(path, source).await,
+        SourceMetadata::Sidecar => write_sidecar(path, source).await,
+    };
+
+    if let Err(report) = result {
+        warn!(
+            "Failed to store the source of '{}': {report:#}",
+            path.display()
+        );
+    }
+}
+
+#[cfg(unix)]
+async fn write_xattrs(path: &Path, source: &Source<'_>) -> Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    let path = path.to_owned();
+    let attributes = [
+        // Shown by file managers, as set by browsers for downloaded files.
+        ("user.xdg.origin.url", source.url.to_owned()),
+        ("user.elopage.lesson_id", source.lesson_id.to_string()),
+        ("user.elopage.course_id", source.course_id.to_string()),
+    ];
+
+    tokio::task::spawn_blocking(move || {
+        // Hard links share their attributes, so the file's other names would be attributed to this source.
+        if std::fs::metadata(&path)?.nlink() > 1 {
+            tracing::debug!(
+                "Not storing the source of '{}' in extended attributes, as it is hard-linked.",
+                path.display()
+            );
+            return Ok(());
+        }
+
+        for (name, value) in attributes {
+            xattr::set(&path, name, value.as_bytes())
+                .wrap_err_with(|| format!("Failed to set extended attribute '{name}'"))?;
+        }
+        Ok(())
+    })
+    .await?
+}
+
+#[cfg(not(unix))]
+async fn write_xattrs(_path: &Path, _source: &Source<'_>) -> Result<()> {
+    color_eyre::eyre::bail!(
+        "Extended attributes are not supported on this platform, use `--source-metadata sidecar`"
+    )
+}
+
+async fn write_sidecar(path: &Path, source: &Source<'_>) -> Result<()> {
+    tokio::fs::write(sidecar_path(path), serde_json::to_vec_pretty(source)?)
+        .await
+        .wrap_err("Failed to write sidecar file")
+}
+
+fn sidecar_path(path: &Path) -> PathBuf {
+    let mut file_name = path.file_name().map(OsString::from).unwrap_or_default();
+    file_name.push(".");
+    file_name.push(SIDECAR_EXTENSION);
+    path.with_file_name(file_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_timestamps() {
+        let time = humantime::parse_rfc3339("2024-05-01T12:34:56Z").unwrap();
+
+        assert_eq!(parse_timestamp("2024-05-01T12:34:56Z"), Some(time));
+        assert_eq!(parse_timestamp(" 2024-05-01T12:34:56 "), Some(time));
+        assert_eq!(
+            parse_timestamp("2024-05-01T12:34:56.250Z"),
+            Some(time + Duration::from_millis(250))
+        );
+        assert_eq!(parse_timestamp("2024-05-01T14:34:56.000+02:00"), Some(time));
+        assert_eq!(parse_timestamp("2024-05-01T07:04:56-05:30"), Some(time));
+        assert_eq!(parse_timestamp("2024-05-01"), None);
+        assert_eq!(parse_timestamp("yesterday"), None);
+        assert_eq!(parse_timestamp(""), None);
+    }
+
+    #[test]
+    fn names_sidecars_after_files() {
+        assert_eq!(
+            sidecar_path(Path::new("course/Intro/video [abc].mp4")),
+            Path::new("course/Intro/video [abc].mp4.source.json")
+        );
+    }
+}
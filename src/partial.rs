@@ -0,0 +1,192 @@
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::SystemTime,
+};
+
+use color_eyre::{eyre::WrapErr, Result};
+use tokio::{
+    fs::{self, File},
+    io::AsyncWriteExt,
+};
+use tracing::{debug, warn};
+
+/// Extension appended to files while they are being downloaded.
+const PARTIAL_EXTENSION: &str = "part";
+
+/// A file being downloaded, written to `<name>.part` and moved into place once complete.
+///
+/// Thus, an interrupted download never leaves a truncated file behind which looks complete.
+/// If the download is cancelled or fails, the partial file is removed.
+#[derive(Debug)]
+pub(crate) struct PartialFile {
+    path: PathBuf,
+    partial_path: PathBuf,
+    file: File,
+    persisted: bool,
+}
+
+impl PartialFile {
+    /// Create the partial file for a download to `path`.
+    pub(crate) async fn create(path: &Path) -> Result<Self> {
+        let partial_path = partial_path(path);
+        let file = File::create(&partial_path).await.wrap_err_with(|| {
+            format!("Failed to create partial file '{}'", partial_path.display())
+        })?;
+
+        Ok(Self {
+            path: path.to_owned(),
+            partial_path,
+            file,
+            persisted: false,
+        })
+    }
+
+    pub(crate) fn file(&mut self) -> &mut File {
+        &mut self.file
+    }
+
+    pub(crate) fn partial_path(&self) -> &Path {
+        &self.partial_path
+    }
+
+    /// Set the modification time of the completed download.
+    pub(crate) async fn set_modified(&mut self, time: SystemTime) -> Result<()> {
+        self.file.flush().await?;
+        let file = self.file.try_clone().await?.into_std().await;
+        tokio::task::spawn_blocking(move || file.set_modified(time))
+            .await?
+            .wrap_err("Failed to set the modification time")
+    }
+
+    /// Flush the completed download to disk and move it into place.
+    pub(crate) async fn persist(mut self) -> Result<()> {
+        self.file.sync_all().await?;
+        fs::rename(&self.partial_path, &self.path)
+            .await
+            .wrap_err_with(|| {
+                format!(
+                    "Failed to move download into place at '{}'",
+                    self.path.display()
+                )
+            })?;
+        self.persisted = true;
+
+        Ok(())
+    }
+}
+
+impl Drop for PartialFile {
+    fn drop(&mut self) {
+        if !self.persisted {
+            debug!("Removing partial file '{}'.", self.partial_path.display());
+            if let Err(error) = std::fs::remove_file(&self.partial_path) {
+                warn!(
+                    "Failed to remove partial file '{}': {error}",
+                    self.partial_path.display()
+                );
+            }
+        }
+    }
+}
+
+fn partial_path(path: &Path) -> PathBuf {
+    let mut file_name = path.file_name().map(OsString::from).unwrap_or_default();
+    file_name.push(".");
+    file_name.push(PARTIAL_EXTENSION);
+    path.with_file_name(file_name)
+}
+
+/// A temporary directory of one `yt-dlp` run in a lesson directory, for its fragments and resume information.
+///
+/// `yt-dlp` moves the completed download out of it, and lists it in it. Whatever it leaves behind when it fails
+/// or is killed is removed, without touching other downloads in the lesson directory.
+#[derive(Debug)]
+pub(crate) struct YtDlpTempDir {
+    path: PathBuf,
+}
+
+impl YtDlpTempDir {
+    /// Create a temporary directory within `dir`, unique to this run.
+    pub(crate) async fn create(dir: &Path) -> Result<Self> {
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+        let path = dir.join(format!(
+            ".yt-dlp-{}-{}",
+            std::process::id(),
+            NEXT_ID.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(&path).await.wrap_err_with(|| {
+            format!(
+                "Failed to create yt-dlp temporary directory '{}'",
+                path.display()
+            )
+        })?;
+
+        Ok(Self { path })
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// File for `yt-dlp` to list the paths of its completed downloads in, with `--print-to-file`.
+    pub(crate) fn downloads_list(&self) -> PathBuf {
+        self.path.join("downloads.txt")
+    }
+
+    /// The paths of the files `yt-dlp` listed as downloaded.
+    pub(crate) async fn downloaded_files(&self) -> Vec<PathBuf> {
+        fs::read_to_string(self.downloads_list())
+            .await
+            .map(|list| {
+                list.lines()
+                    .filter(|line| !line.is_empty())
+                    .map(PathBuf::from)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+impl Drop for YtDlpTempDir {
+    fn drop(&mut self) {
+        match std::fs::remove_dir_all(&self.path) {
+            Ok(()) => debug!(
+                "Removed yt-dlp temporary directory '{}'.",
+                self.path.display()
+            ),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {}
+            Err(error) => warn!(
+                "Failed to remove yt-dlp temporary directory '{}': {error}",
+                self.path.display()
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+    use crate::sanitize::{Normalization, SanitizeProfile, Sanitizer};
+
+    #[tokio::test]
+    async fn downloads_files_with_names_of_maximum_length() {
+        let dir = tempfile::tempdir().unwrap();
+        let sanitizer = Sanitizer::new(SanitizeProfile::Posix, Normalization::None).for_files();
+        let name = sanitizer.finalize(&format!("{}.pdf", "a".repeat(300)));
+        let path = dir.path().join(&name);
+
+        let mut partial = PartialFile::create(&path).await.unwrap();
+        partial.file().write_all(b"%PDF").await.unwrap();
+        partial.persist().await.unwrap();
+
+        assert!(name.ends_with(".pdf"));
+        assert_eq!(std::fs::read(&path).unwrap(), b"%PDF");
+        // The sidecar file, the longest suffixed name, fits the file system's limit of 255 bytes as well.
+        std::fs::write(dir.path().join(format!("{name}.source.json")), "{}").unwrap();
+    }
+}
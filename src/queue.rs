@@ -0,0 +1,312 @@
+use std::{cmp::Reverse, collections::HashMap, future::Future};
+
+use clap::ValueEnum;
+use color_eyre::Result;
+use futures::{stream::FuturesUnordered, FutureExt, StreamExt};
+use tracing::{debug, instrument, Level};
+
+use crate::{shutdown::Shutdown, Id, PlannedDownload};
+
+/// Order in which queued downloads are started.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub(crate) enum Schedule {
+    /// Lessons and assets in the order of the course.
+    CourseOrder,
+    /// Smallest files first, so that many small files are not held up by a few large videos.
+    SmallestFirst,
+    /// Largest files first, so that the longest downloads start early.
+    LargestFirst,
+}
+
+/// Concurrency limits of the work queue.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Limits {
+    /// Maximum number of lessons with downloads in flight.
+    pub lessons: usize,
+    /// Maximum number of downloads in flight.
+    pub assets: usize,
+}
+
+/// A queued download, as far as scheduling is concerned.
+pub(crate) trait Queued {
+    fn lesson_id(&self) -> Id;
+    /// Index of the asset in order of appearance within its lesson.
+    fn index(&self) -> usize;
+    fn estimated_size(&self) -> Option<u64>;
+}
+
+impl Queued for PlannedDownload {
+    fn lesson_id(&self) -> Id {
+        self.lesson.id
+    }
+
+    fn index(&self) -> usize {
+        self.index
+    }
+
+    fn estimated_size(&self) -> Option<u64> {
+        self.estimated_size
+    }
+}
+
+/// Run the downloads, starting queued downloads with `start` in the order of `schedule` as long as the limits allow.
+///
+/// No new downloads are started once shutdown is requested. The first failed download cancels the others.
+#[instrument(level = Level::DEBUG, skip(downloads, start, shutdown))]
+pub(crate) async fn run<D: Queued, F: Future<Output = Result<()>>>(
+    mut downloads: Vec<D>,
+    mut start: impl FnMut(D) -> F,
+    limits: Limits,
+    schedule: Schedule,
+    shutdown: &Shutdown,
+) -> Result<()> {
+    // Downloads of unknown size are started last when ordering by size. The sort is stable, keeping course order for ties.
+    match schedule {
+        Schedule::CourseOrder => {}
+        Schedule::SmallestFirst => downloads.sort_by_key(|download| {
+            (
+                download.estimated_size().is_none(),
+                download.estimated_size(),
+            )
+        }),
+        Schedule::LargestFirst => downloads.sort_by_key(|download| {
+            (
+                download.estimated_size().is_none(),
+                Reverse(download.estimated_size()),
+            )
+        }),
+    }
+
+    let limits = Limits {
+        lessons: limits.lessons.max(1),
+        assets: limits.assets.max(1),
+    };
+    let mut queue = downloads;
+    let mut in_flight = FuturesUnordered::new();
+    // Number of downloads in flight, per lesson ID.
+    let mut active_lessons: HashMap<Id, usize> = HashMap::new();
+
+    loop {
+        // Start as many queued downloads as the limits allow, in schedule order.
+        while in_flight.len() < limits.assets && !shutdown.is_stopping() {
+            let Some(position) = queue.iter().position(|download| {
+                active_lessons.contains_key(&download.lesson_id())
+                    || active_lessons.len() < limits.lessons
+            }) else {
+                break;
+            };
+
+            let download = queue.remove(position);
+            let lesson_id = download.lesson_id();
+            debug!(
+                "Starting download {} of lesson ID '{lesson_id}'.",
+                download.index() + 1
+            );
+
+            *active_lessons.entry(lesson_id).or_default() += 1;
+            in_flight.push(start(download).map(move |result| (lesson_id, result)));
+        }
+
+        // Shortcut on download errors, cancelling the downloads in flight.
+        let Some((lesson_id, result)) = in_flight.next().await else {
+            return Ok(());
+        };
+        result?;
+
+        if let Some(active) = active_lessons.get_mut(&lesson_id) {
+            *active -= 1;
+            if *active == 0 {
+                active_lessons.remove(&lesson_id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use color_eyre::eyre::eyre;
+    use tokio::time::sleep;
+
+    use super::*;
+    use crate::lock;
+
+    #[derive(Debug)]
+    struct Job {
+        lesson_id: Id,
+        index: usize,
+        size: Option<u64>,
+    }
+
+    impl Queued for Job {
+        fn lesson_id(&self) -> Id {
+            self.lesson_id
+        }
+
+        fn index(&self) -> usize {
+            self.index
+        }
+
+        fn estimated_size(&self) -> Option<u64> {
+            self.size
+        }
+    }
+
+    fn jobs(sizes: &[(Id, Option<u64>)]) -> Vec<Job> {
+        sizes
+            .iter()
+            .enumerate()
+            .map(|(index, &(lesson_id, size))| Job {
+                lesson_id,
+                index,
+                size,
+            })
+            .collect()
+    }
+
+    /// Run the jobs one at a time, and return their indices in the order they were started.
+    async fn started_order(jobs: Vec<Job>, schedule: Schedule) -> Vec<usize> {
+        let started = Mutex::new(Vec::new());
+        let limits = Limits {
+            lessons: 1,
+            assets: 1,
+        };
+        run(
+            jobs,
+            |job| {
+                lock(&started).push(job.index);
+                async { Ok(()) }
+            },
+            limits,
+            schedule,
+            &Shutdown::manual(),
+        )
+        .await
+        .unwrap();
+        started.into_inner().unwrap()
+    }
+
+    #[tokio::test]
+    async fn orders_downloads_by_schedule() {
+        let sizes = [(1, Some(30)), (1, None), (2, Some(10)), (2, Some(20))];
+
+        assert_eq!(
+            started_order(jobs(&sizes), Schedule::CourseOrder).await,
+            [0, 1, 2, 3]
+        );
+        assert_eq!(
+            started_order(jobs(&sizes), Schedule::SmallestFirst).await,
+            [2, 3, 0, 1]
+        );
+        assert_eq!(
+            started_order(jobs(&sizes), Schedule::LargestFirst).await,
+            [0, 3, 2, 1]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn respects_the_limits() {
+        let in_flight = Arc::new(Mutex::new(HashMap::<Id, usize>::new()));
+        let peak = Arc::new(Mutex::new((0, 0)));
+        let limits = Limits {
+            lessons: 2,
+            assets: 3,
+        };
+
+        run(
+            jobs(&[
+                (1, None),
+                (1, None),
+                (1, None),
+                (2, None),
+                (3, None),
+                (3, None),
+            ]),
+            |job| {
+                let in_flight = in_flight.clone();
+                let peak = peak.clone();
+                async move {
+                    {
+                        let mut in_flight = lock(&in_flight);
+                        *in_flight.entry(job.lesson_id).or_default() += 1;
+                        let mut peak = lock(&peak);
+                        peak.0 = peak.0.max(in_flight.len());
+                        peak.1 = peak.1.max(in_flight.values().sum());
+                    }
+                    sleep(Duration::from_secs(1)).await;
+                    let mut in_flight = lock(&in_flight);
+                    *in_flight.get_mut(&job.lesson_id).unwrap() -= 1;
+                    in_flight.retain(|_, active| *active > 0);
+                    Ok(())
+                }
+            },
+            limits,
+            Schedule::CourseOrder,
+            &Shutdown::manual(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(*lock(&peak), (2, 3));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cancels_downloads_on_the_first_error() {
+        let finished = Arc::new(Mutex::new(Vec::new()));
+        let limits = Limits {
+            lessons: 3,
+            assets: 3,
+        };
+
+        let result = run(
+            jobs(&[(1, None), (2, None), (3, None), (4, None)]),
+            |job| {
+                let finished = finished.clone();
+                async move {
+                    if job.index == 1 {
+                        return Err(eyre!("Download failed"));
+                    }
+                    sleep(Duration::from_secs(1)).await;
+                    lock(&finished).push(job.index);
+                    Ok(())
+                }
+            },
+            limits,
+            Schedule::CourseOrder,
+            &Shutdown::manual(),
+        )
+        .await;
+
+        assert!(result.is_err());
+        assert!(lock(&finished).is_empty());
+    }
+
+    #[tokio::test]
+    async fn starts_no_downloads_after_stopping() {
+        let shutdown = Shutdown::manual();
+        shutdown.stop();
+
+        let started = Mutex::new(0);
+        run(
+            jobs(&[(1, None), (2, None)]),
+            |_| {
+                *lock(&started) += 1;
+                async { Ok(()) }
+            },
+            Limits {
+                lessons: 1,
+                assets: 1,
+            },
+            Schedule::CourseOrder,
+            &shutdown,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(started.into_inner().unwrap(), 0);
+    }
+}
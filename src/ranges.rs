@@ -0,0 +1,288 @@
+use std::{io::SeekFrom, path::Path};
+
+use color_eyre::{
+    eyre::{bail, eyre},
+    Result,
+};
+use futures::{future::try_join_all, StreamExt};
+use reqwest::{
+    header::{ACCEPT_ENCODING, ACCEPT_RANGES, RANGE},
+    Client, Response, StatusCode,
+};
+use tokio::{
+    fs::OpenOptions,
+    io::{AsyncSeekExt, AsyncWriteExt},
+};
+use tracing::{debug, instrument, Level};
+
+use crate::{partial::PartialFile, rate::RateLimiter, throttle::Throttle};
+
+/// Number of attempts to download a range, each resuming where the previous one was interrupted.
+const MAX_RANGE_ATTEMPTS: u32 = 3;
+
+/// Whether a response announces that the server serves byte ranges of the file.
+pub(crate) fn accepts_ranges(response: &Response) -> bool {
+    response
+        .headers()
+        .get(ACCEPT_RANGES)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.eq_ignore_ascii_case("bytes"))
+}
+
+/// Download a file of `length` bytes as `connections` ranges at the same time, into a preallocated partial file.
+///
+/// Each range is verified to be complete, as is the assembled file's length.
+/// Returns `false` if the server answered a range request with the whole file instead,
+/// in which case the partial file has to be downloaded over a single connection.
+#[instrument(level = Level::DEBUG, skip(client, throttle, file, rate_limiter))]
+pub(crate) async fn download_ranges(
+    client: &Client,
+    throttle: &Throttle,
+    url: &str,
+    file: &mut PartialFile,
+    length: u64,
+    connections: usize,
+    rate_limiter: &RateLimiter,
+) -> Result<bool> {
+    file.file().set_len(length).await?;
+
+    let range_length = length.div_ceil(connections.max(1) as u64);
+    let ranges = (0..connections as u64)
+        .map(|index| {
+            (
+                index * range_length,
+                ((index + 1) * range_length).min(length),
+            )
+        })
+        .filter(|(start, end)| start < end);
+
+    let served = try_join_all(ranges.map(|(start, end)| {
+        download_range(
+            client,
+            throttle,
+            url,
+            file.partial_path(),
+            start,
+            end,
+            rate_limiter,
+        )
+    }))
+    .await?;
+    if !served.into_iter().all(|served| served) {
+        return Ok(false);
+    }
+
+    file.file().sync_all().await?;
+    let assembled_length = file.file().metadata().await?.len();
+    if assembled_length != length {
+        bail!("Assembled {assembled_length} of {length} bytes of '{url}'");
+    }
+
+    Ok(true)
+}
+
+/// Download the bytes from `start` up to, but excluding, `end` into their place in the partial file.
+///
+/// An interrupted range is resumed where it stopped. Returns `false` if the server did not serve the range,
+/// in which case the bytes written so far have to be discarded.
+#[instrument(level = Level::DEBUG, skip(client, throttle, rate_limiter))]
+async fn download_range(
+    client: &Client,
+    throttle: &Throttle,
+    url: &str,
+    partial_path: &Path,
+    start: u64,
+    end: u64,
+    rate_limiter: &RateLimiter,
+) -> Result<bool> {
+    let mut file = OpenOptions::new().write(true).open(partial_path).await?;
+    file.seek(SeekFrom::Start(start)).await?;
+
+    let expected = end - start;
+    let mut received = 0;
+    for attempt in 1.. {
+        let offset = start + received;
+        let response = throttle
+            .send_in_slot(url, || {
+                client
+                    .get(url)
+                    .header(RANGE, format!("bytes={offset}-{}", end - 1))
+                    // Ranges refer to the file as stored, not to a compressed transfer.
+                    .header(ACCEPT_ENCODING, "identity")
+            })
+            .await?;
+        if response.status() != StatusCode::PARTIAL_CONTENT {
+            debug!(
+                "Server did not serve the range {offset}-{end} of '{url}' ({}).",
+                response.status()
+            );
+            return Ok(false);
+        }
+
+        let mut stream = response.bytes_stream();
+        let interruption = loop {
+            let chunk = match stream.next().await {
+                Some(Ok(chunk)) => chunk,
+                Some(Err(error)) => break Some(error),
+                None => break None,
+            };
+            // Never overwrite the next range.
+            if received + chunk.len() as u64 > expected {
+                bail!("Server sent more than the range {start}-{end} of '{url}'");
+            }
+
+            rate_limiter.consume(chunk.len()).await;
+            file.write_all(&chunk).await?;
+            received += chunk.len() as u64;
+        };
+
+        if received == expected {
+            break;
+        }
+        if attempt == MAX_RANGE_ATTEMPTS {
+            return Err(match interruption {
+                Some(error) => error.into(),
+                None => eyre!(
+                    "Received {received} of {expected} bytes of the range {start}-{end} of '{url}'"
+                ),
+            });
+        }
+        debug!("Resuming the range {start}-{end} of '{url}' after {received} of {expected} bytes.");
+    }
+    file.flush().await?;
+    debug!("Finished range {start}-{end} of '{url}'.");
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use wiremock::{
+        matchers::{header, method},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::*;
+
+    /// Download the range `start..end` of the file served by `server` into a file of `length` zeros.
+    async fn range(
+        server: &MockServer,
+        start: u64,
+        end: u64,
+        length: u64,
+    ) -> (Result<bool>, Vec<u8>) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file.part");
+        std::fs::write(&path, vec![b'0'; length as usize]).unwrap();
+
+        let result = download_range(
+            &Client::new(),
+            &Throttle::new(1, &[], true),
+            &format!("{}/file", server.uri()),
+            &path,
+            start,
+            end,
+            &RateLimiter::new(None, 1),
+        )
+        .await;
+
+        (result, std::fs::read(&path).unwrap())
+    }
+
+    fn partial(body: &str) -> ResponseTemplate {
+        ResponseTemplate::new(206).set_body_string(body)
+    }
+
+    #[tokio::test]
+    async fn writes_ranges_into_place() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(header("range", "bytes=2-5"))
+            .respond_with(partial("cdef"))
+            .mount(&server)
+            .await;
+
+        let (result, contents) = range(&server, 2, 6, 8).await;
+        assert!(result.unwrap());
+        assert_eq!(contents, b"00cdef00");
+    }
+
+    #[tokio::test]
+    async fn resumes_interrupted_ranges() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(header("range", "bytes=0-3"))
+            .respond_with(partial("ab"))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(header("range", "bytes=2-3"))
+            .respond_with(partial("cd"))
+            .mount(&server)
+            .await;
+
+        let (result, contents) = range(&server, 0, 4, 4).await;
+        assert!(result.unwrap());
+        assert_eq!(contents, b"abcd");
+    }
+
+    #[tokio::test]
+    async fn rejects_wrong_lengths() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(header("range", "bytes=0-1"))
+            .respond_with(partial("abc"))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(header("range", "bytes=4-7"))
+            .respond_with(partial(""))
+            .expect(MAX_RANGE_ATTEMPTS as u64)
+            .mount(&server)
+            .await;
+
+        let (result, contents) = range(&server, 0, 2, 8).await;
+        assert!(result.is_err());
+        // The next range is never overwritten.
+        assert_eq!(&contents[2..], b"000000");
+
+        let (result, _) = range(&server, 4, 8, 8).await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn handles_statuses() {
+        let server = MockServer::start().await;
+        // The whole file instead of the range.
+        Mock::given(method("GET"))
+            .and(header("range", "bytes=0-3"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("abcdefgh"))
+            .mount(&server)
+            .await;
+        // Throttled once.
+        Mock::given(method("GET"))
+            .and(header("range", "bytes=4-7"))
+            .respond_with(ResponseTemplate::new(429).insert_header("retry-after", "0"))
+            .up_to_n_times(1)
+            .with_priority(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(header("range", "bytes=4-7"))
+            .respond_with(partial("efgh"))
+            .mount(&server)
+            .await;
+
+        let (result, contents) = range(&server, 0, 4, 8).await;
+        assert!(!result.unwrap());
+        assert_eq!(contents, b"00000000");
+
+        let (result, contents) = range(&server, 4, 8, 8).await;
+        assert!(result.unwrap());
+        assert_eq!(contents, b"0000efgh");
+
+        // Not found.
+        let (result, _) = range(&server, 8, 12, 12).await;
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,275 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, SystemTime},
+};
+
+use tokio::time::{sleep, Instant};
+use tracing::{info, warn};
+
+use crate::{
+    lock,
+    space::{format_size, parse_size},
+};
+
+/// Interval at which the `--limit-rate-file` is checked for changes.
+const CONTROL_FILE_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Global download rate limit: a token bucket holding up to one second's worth of bytes.
+///
+/// The rate is split into one share per parallel download. `yt-dlp` processes, which limit their own rate,
+/// take a share out of the bucket while they run, and native downloads share the rest.
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    /// The rate passed on the command line, in bytes per second. 0 means unlimited.
+    default_rate: u64,
+    /// Number of shares the rate is split into.
+    parallel: usize,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Bytes per second. 0 means unlimited.
+    rate: u64,
+    /// Number of shares taken by running `yt-dlp` processes.
+    reserved: usize,
+    /// Available bytes. Negative while downloads are waiting for reserved bytes.
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// A share of the rate limit taken by a `yt-dlp` process, returned to native downloads once dropped.
+#[derive(Debug)]
+pub(crate) struct RateShare {
+    rate_limiter: Arc<RateLimiter>,
+    /// Bytes per second the process may use.
+    pub rate: u64,
+}
+
+impl RateLimiter {
+    /// Limit the rate of up to `parallel` downloads at the same time.
+    pub(crate) fn new(rate: Option<u64>, parallel: usize) -> Self {
+        let rate = rate.unwrap_or(0);
+
+        Self {
+            default_rate: rate,
+            parallel: parallel.max(1),
+            bucket: Mutex::new(Bucket {
+                rate,
+                reserved: 0,
+                tokens: 0.0,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    /// Take one download's share of the rate limit out of the bucket, or `None` if unlimited.
+    ///
+    /// Changes of the rate limit do not affect shares taken before.
+    pub(crate) fn take_share(self: &Arc<Self>) -> Option<RateShare> {
+        let mut bucket = self.bucket();
+        if bucket.rate == 0 {
+            return None;
+        }
+
+        bucket.reserved += 1;
+        Some(RateShare {
+            rate_limiter: self.clone(),
+            rate: (bucket.rate / self.parallel as u64).max(1),
+        })
+    }
+
+    /// Change the rate limit. 0 means unlimited.
+    fn set_rate(&self, rate: u64) {
+        let mut bucket = self.bucket();
+        if bucket.rate != rate {
+            info!(
+                "Limiting the download rate to {}.",
+                match rate {
+                    0 => "unlimited".into(),
+                    rate => format!("{}/s", format_size(rate)),
+                }
+            );
+            bucket.rate = rate;
+            bucket.tokens = 0.0;
+            bucket.last_refill = Instant::now();
+        }
+    }
+
+    /// Wait until `bytes` may be transferred without exceeding the rate limit.
+    pub(crate) async fn consume(&self, bytes: usize) {
+        let wait = {
+            let mut bucket = self.bucket();
+            if bucket.rate == 0 {
+                return;
+            }
+
+            let now = Instant::now();
+            // Native downloads keep at least one share, should more `yt-dlp` processes run than expected.
+            let shares = self.parallel.saturating_sub(bucket.reserved).max(1);
+            let rate = (bucket.rate * shares as u64 / self.parallel as u64).max(1) as f64;
+            bucket.tokens = (bucket.tokens
+                + now.duration_since(bucket.last_refill).as_secs_f64() * rate)
+                .min(rate);
+            bucket.last_refill = now;
+
+            // Reserve the bytes right away, so that concurrent downloads queue up behind each other.
+            bucket.tokens -= bytes as f64;
+            (bucket.tokens < 0.0).then(|| Duration::from_secs_f64(-bucket.tokens / rate))
+        };
+
+        if let Some(wait) = wait {
+            sleep(wait).await;
+        }
+    }
+
+    /// Poll a control file for rate changes, such as `2M`, or `0` for no limit.
+    ///
+    /// If the file is removed, the rate passed on the command line applies again.
+    /// Polling stops once the rate limiter is dropped.
+    pub(crate) fn watch_control_file(self: &Arc<Self>, path: PathBuf) {
+        let rate_limiter = Arc::downgrade(self);
+
+        tokio::spawn(async move {
+            let mut last_modified: Option<SystemTime> = None;
+
+            loop {
+                let Some(rate_limiter) = rate_limiter.upgrade() else {
+                    return;
+                };
+
+                rate_limiter
+                    .poll_control_file(&path, &mut last_modified)
+                    .await;
+
+                drop(rate_limiter);
+                sleep(CONTROL_FILE_POLL_INTERVAL).await;
+            }
+        });
+    }
+
+    /// Apply the rate in the control file, if it changed since `last_modified`.
+    async fn poll_control_file(&self, path: &Path, last_modified: &mut Option<SystemTime>) {
+        let modified = tokio::fs::metadata(path)
+            .await
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        if modified == *last_modified {
+            return;
+        }
+        *last_modified = modified;
+
+        match modified {
+            Some(_) => match tokio::fs::read_to_string(path).await {
+                Ok(content) => match parse_size(&content) {
+                    Ok(rate) => self.set_rate(rate),
+                    Err(error) => warn!("Ignoring invalid rate in '{}': {error}", path.display()),
+                },
+                Err(error) => warn!("Failed to read '{}': {error}", path.display()),
+            },
+            None => self.set_rate(self.default_rate),
+        }
+    }
+
+    fn bucket(&self) -> MutexGuard<'_, Bucket> {
+        lock(&self.bucket)
+    }
+}
+
+impl Drop for RateShare {
+    fn drop(&mut self) {
+        self.rate_limiter.bucket().reserved -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// How long it takes to transfer `bytes`, in virtual time.
+    async fn transfer_time(rate_limiter: &RateLimiter, bytes: usize) -> Duration {
+        let start = Instant::now();
+        rate_limiter.consume(bytes).await;
+        start.elapsed()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn limits_the_rate() {
+        let rate_limiter = RateLimiter::new(Some(1_000), 2);
+
+        assert_eq!(
+            transfer_time(&rate_limiter, 500).await,
+            Duration::from_millis(500)
+        );
+        // Bytes are reserved right away, so that downloads queue up behind each other.
+        rate_limiter.consume(1_000).await;
+        assert_eq!(
+            transfer_time(&rate_limiter, 500).await,
+            Duration::from_millis(500)
+        );
+
+        let unlimited = RateLimiter::new(None, 2);
+        assert_eq!(transfer_time(&unlimited, 1_000_000).await, Duration::ZERO);
+        assert!(Arc::new(unlimited).take_share().is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn takes_shares_out_of_the_bucket() {
+        let rate_limiter = Arc::new(RateLimiter::new(Some(1_000), 4));
+
+        let share = rate_limiter.take_share().unwrap();
+        assert_eq!(share.rate, 250);
+        assert_eq!(
+            transfer_time(&rate_limiter, 750).await,
+            Duration::from_secs(1)
+        );
+
+        drop(share);
+        assert_eq!(rate_limiter.bucket().reserved, 0);
+
+        // Native downloads keep a share, should more yt-dlp processes run than expected.
+        let rate_limiter = Arc::new(RateLimiter::new(Some(1_000), 4));
+        let _shares: Vec<_> = (0..5).filter_map(|_| rate_limiter.take_share()).collect();
+        assert_eq!(
+            transfer_time(&rate_limiter, 250).await,
+            Duration::from_secs(1)
+        );
+    }
+
+    #[tokio::test]
+    async fn applies_the_control_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("rate.txt");
+        let rate_limiter = RateLimiter::new(Some(1_000), 1);
+        let mut last_modified = None;
+
+        std::fs::write(&path, "2k\n").unwrap();
+        rate_limiter
+            .poll_control_file(&path, &mut last_modified)
+            .await;
+        assert_eq!(rate_limiter.bucket().rate, 2_000);
+
+        // Invalid rates are ignored.
+        std::fs::write(&path, "fast").unwrap();
+        last_modified = None;
+        rate_limiter
+            .poll_control_file(&path, &mut last_modified)
+            .await;
+        assert_eq!(rate_limiter.bucket().rate, 2_000);
+
+        std::fs::write(&path, "0").unwrap();
+        last_modified = None;
+        rate_limiter
+            .poll_control_file(&path, &mut last_modified)
+            .await;
+        assert_eq!(rate_limiter.bucket().rate, 0);
+
+        // Without the file, the rate passed on the command line applies again.
+        std::fs::remove_file(&path).unwrap();
+        rate_limiter
+            .poll_control_file(&path, &mut last_modified)
+            .await;
+        assert_eq!(rate_limiter.bucket().rate, 1_000);
+    }
+}
@@ -0,0 +1,258 @@
+use std::{collections::BTreeMap, path::Path, sync::Arc};
+
+use color_eyre::{
+    eyre::{bail, WrapErr},
+    Result,
+};
+use tokio::fs::create_dir_all;
+use tracing::{info, instrument, warn, Level};
+
+use crate::{
+    args::RepairArgs,
+    collect_lesson_assets, fetch_lesson_content_blocks, fetch_lessons_list,
+    integrity::{self, FileStatus},
+    open_course, run_downloads,
+    shutdown::Shutdown,
+    state::{AssetState, ItemState, State},
+    summary::Summary,
+    template::{index_width, Variable},
+    Context, Id, LessonAsset, LessonDir, PlannedDownload,
+};
+
+/// A recorded asset which is missing, corrupt, or was not downloaded completely.
+#[derive(Debug)]
+struct BrokenAsset {
+    lesson_id: Id,
+    /// Index of the asset in order of appearance within its lesson.
+    index: usize,
+    asset: AssetState,
+    problem: String,
+}
+
+/// Download the missing, corrupt or incomplete files of an offline-cached course again.
+///
+/// Only the affected lessons are fetched from elopage again, to resolve current download URLs.
+#[instrument(level = Level::DEBUG, skip(args, shutdown))]
+pub(crate) async fn run(args: RepairArgs, shutdown: &Shutdown) -> Result<()> {
+    let download_args = &args.download;
+    let opened = open_course(download_args).await?;
+
+    let recorded_state = State::load(&opened.path).await?;
+    if recorded_state.items.is_empty() {
+        bail!(
+            "No offline-cached course found at '{}'. Download the course first.",
+            opened.path.display()
+        );
+    }
+
+    let broken = find_broken_assets(&opened.path, &recorded_state).await?;
+    if broken.is_empty() {
+        println!("All files are intact. Nothing to repair.");
+        return Ok(());
+    }
+    for broken_asset in &broken {
+        println!(
+            "{}: {}",
+            broken_asset.problem,
+            describe(
+                &recorded_state.items[&broken_asset.lesson_id],
+                &broken_asset.asset
+            )
+        );
+    }
+    if args.dry_run {
+        return Ok(());
+    }
+
+    // Broken assets are recorded as incomplete, so that an interrupted repair, or the next run in mirror mode, downloads them again.
+    let mut state = recorded_state.clone();
+    for broken_asset in &broken {
+        if let Some(asset) = state
+            .items
+            .get_mut(&broken_asset.lesson_id)
+            .and_then(|item| item.assets.get_mut(broken_asset.index))
+        {
+            asset.pending = true;
+            asset.checksum = None;
+            asset.validators = None;
+        }
+    }
+    state.save(&opened.path).await?;
+
+    let lessons_list = fetch_lessons_list(
+        opened.authenticated_client.clone(),
+        &opened.throttle,
+        download_args.course_id(),
+    )
+    .await?;
+    let course_vars = opened.vars.clone();
+    let context = Arc::new(Context::new(
+        download_args,
+        opened,
+        recorded_state.clone(),
+        state,
+        Summary::default(),
+    ));
+
+    let mut broken_by_lesson: BTreeMap<Id, Vec<BrokenAsset>> = BTreeMap::new();
+    for broken_asset in broken {
+        broken_by_lesson
+            .entry(broken_asset.lesson_id)
+            .or_default()
+            .push(broken_asset);
+    }
+
+    let mut downloads = Vec::new();
+    let mut unrepairable = 0;
+    for (lesson_id, broken_assets) in broken_by_lesson {
+        let item = &recorded_state.items[&lesson_id];
+        let Some(content_page_id) = lessons_list
+            .iter()
+            .find(|lesson| lesson.id == lesson_id && lesson.active)
+            .and_then(|lesson| lesson.content_page_id)
+        else {
+            warn!(
+                "Lesson '{}' was removed from the course. Its files cannot be repaired.",
+                item.name
+            );
+            unrepairable += broken_assets.len();
+            continue;
+        };
+
+        info!("Processing lesson ID '{lesson_id}'...");
+        let content_blocks = fetch_lesson_content_blocks(
+            context.authenticated_client.clone(),
+            &context.throttle,
+            context.course_id,
+            lesson_id,
+            content_page_id,
+        )
+        .await?;
+        let assets = collect_lesson_assets(content_blocks, lesson_id, &context).await;
+
+        // The lesson's template variables, as resolved when placing the lesson.
+        let mut vars = course_vars.clone();
+        if let Some(category) = recorded_state.items.values().find(|category| {
+            category.is_category && Some(category.path.as_path()) == item.path.parent()
+        }) {
+            vars = vars.text(Variable::Parent, &category.name);
+        }
+        let lesson = Arc::new(LessonDir {
+            id: lesson_id,
+            path: context.course_path.join(&item.path),
+            vars: vars
+                .text(Variable::Lesson, &item.name)
+                .number(Variable::LessonId, lesson_id, 0),
+        });
+        create_dir_all(&lesson.path)
+            .await
+            .wrap_err("Failed to create lesson path")?;
+
+        let width = index_width(assets.len());
+        for broken_asset in broken_assets {
+            let Some(mut asset) = assets.get(broken_asset.index).cloned() else {
+                warn!(
+                    "{} is no longer part of the lesson. Run a full sync to update the lesson.",
+                    describe(item, &broken_asset.asset)
+                );
+                unrepairable += 1;
+                continue;
+            };
+
+            // Download the video rendition which was selected before.
+            if let Some(size) = broken_asset.asset.size {
+                asset.select_rendition(size);
+            }
+            let estimated_size = match &asset {
+                LessonAsset::Video { size, .. } => Some(*size as u64),
+                _ => None,
+            };
+
+            let download = PlannedDownload {
+                lesson: lesson.clone(),
+                index: broken_asset.index,
+                asset,
+                vars: lesson
+                    .vars
+                    .clone()
+                    .number(Variable::Index, broken_asset.index + 1, width),
+                file_name: broken_asset.asset.file_name.clone(),
+                estimated_size,
+                is_stored: false,
+                checksum: None,
+                validators: None,
+            };
+            if !download.asset_state().is_unchanged(&broken_asset.asset) {
+                warn!(
+                    "{} changed since it was downloaded. Run a full sync to update the lesson.",
+                    describe(item, &broken_asset.asset)
+                );
+                unrepairable += 1;
+                continue;
+            }
+
+            downloads.push(download);
+        }
+    }
+
+    let repairs = downloads.len();
+    run_downloads(downloads, &context, download_args, shutdown).await?;
+
+    if shutdown.is_stopping() {
+        warn!("Stopped before all files were repaired. Run again to resume.");
+    } else {
+        info!("Repaired {repairs} file(s).");
+    }
+    if unrepairable > 0 {
+        bail!("{unrepairable} file(s) could not be repaired.");
+    }
+
+    Ok(())
+}
+
+/// Find the assets of a course which are missing, corrupt or were not downloaded completely.
+async fn find_broken_assets(course_path: &Path, state: &State) -> Result<Vec<BrokenAsset>> {
+    let mut broken = Vec::new();
+
+    for (&lesson_id, item) in &state.items {
+        for (index, asset) in item.assets.iter().enumerate() {
+            let status = if asset.pending {
+                Some("INCOMPLETE".to_owned())
+            } else if let Some(file_name) = &asset.file_name {
+                let path = course_path.join(&item.path).join(file_name);
+                match &asset.checksum {
+                    Some(checksum) => match integrity::verify_file(&path, checksum).await? {
+                        FileStatus::Intact => None,
+                        status => Some(status.to_string()),
+                    },
+                    None => (!path.exists()).then(|| FileStatus::Missing.to_string()),
+                }
+            } else {
+                None
+            };
+
+            if let Some(problem) = status {
+                broken.push(BrokenAsset {
+                    lesson_id,
+                    index,
+                    asset: asset.clone(),
+                    problem,
+                });
+            }
+        }
+    }
+
+    Ok(broken)
+}
+
+/// Describe an asset for humans, by its path within the course, or by its URL for embedded videos.
+fn describe(item: &ItemState, asset: &AssetState) -> String {
+    match &asset.file_name {
+        Some(file_name) => format!("'{}'", item.path.join(file_name).display()),
+        None => format!(
+            "Embedded video '{}' in '{}'",
+            asset.source,
+            item.path.display()
+        ),
+    }
+}
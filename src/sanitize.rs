@@ -0,0 +1,357 @@
+use std::{collections::HashSet, fmt::Debug};
+
+use clap::ValueEnum;
+use tracing::{instrument, Level};
+use unicode_normalization::UnicodeNormalization;
+
+/// File system compatibility profile for sanitizing file and directory names.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub(crate) enum SanitizeProfile {
+    /// Linux and macOS: only `/` and NUL are replaced, names are limited to 255 bytes.
+    Posix,
+    /// NTFS on Windows: reserved characters and device names (`CON`, `NUL`, ...) are replaced,
+    /// trailing dots and spaces are removed, names are limited to 255 UTF-16 code units.
+    Windows,
+    /// exFAT, as used on USB sticks and SD cards: as Windows, since their files are commonly copied to Windows.
+    Exfat,
+    /// FAT32: as Windows.
+    Fat32,
+}
+
+/// Unicode normalization form applied to file and directory names.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub(crate) enum Normalization {
+    /// Composed form, as commonly used on Linux and Windows.
+    Nfc,
+    /// Decomposed form, as used by macOS HFS+.
+    Nfd,
+    /// Keep names as served by elopage.
+    None,
+}
+
+/// Device names which cannot be used as file names on Windows, regardless of extension.
+const WINDOWS_RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Maximum length of a name, in bytes (POSIX) or UTF-16 code units (Windows, exFAT, FAT32).
+const MAX_NAME_LENGTH: usize = 255;
+
+/// Room kept free in file names for the longest suffix of their temporary and sidecar files, such as
+/// `<file>.part`, `<file>.link` and `<file>.source.json`.
+const FILE_SUFFIX_RESERVE: usize = ".source.json".len();
+
+/// Extensions longer than this are considered part of the name when truncating.
+const MAX_EXTENSION_LENGTH: usize = 16;
+
+/// Makes names of files and directories safe for the selected file system.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Sanitizer {
+    profile: SanitizeProfile,
+    normalization: Normalization,
+    max_length: usize,
+}
+
+impl Sanitizer {
+    pub(crate) fn new(profile: SanitizeProfile, normalization: Normalization) -> Self {
+        Self {
+            profile,
+            normalization,
+            max_length: MAX_NAME_LENGTH,
+        }
+    }
+
+    /// A sanitizer for names of downloaded files, which keeps room for the suffixes of their temporary and sidecar files.
+    pub(crate) fn for_files(self) -> Self {
+        Self {
+            max_length: MAX_NAME_LENGTH - FILE_SUFFIX_RESERVE,
+            ..self
+        }
+    }
+
+    pub(crate) fn profile(&self) -> SanitizeProfile {
+        self.profile
+    }
+
+    /// Replace characters which are not path-safe, such as a template variable value which is going to be part of a name.
+    ///
+    /// Path separators are always replaced, so that a value cannot create subdirectories.
+    #[instrument(level = Level::TRACE)]
+    pub(crate) fn sanitize_value(&self, s: impl AsRef<str> + Debug) -> String {
+        let s = htmlize::unescape(s.as_ref());
+        let s: String = match self.normalization {
+            Normalization::Nfc => s.nfc().collect(),
+            Normalization::Nfd => s.nfd().collect(),
+            Normalization::None => s.into_owned(),
+        };
+
+        let s = s
+            .replace(" / ", " - ")
+            .replace('/', " - ")
+            .replace('\0', "");
+
+        let s = match self.profile {
+            SanitizeProfile::Posix => s,
+            SanitizeProfile::Windows | SanitizeProfile::Exfat | SanitizeProfile::Fat32 => s
+                .replace(": ", " - ")
+                .replace(['*', '\\', '|'], "-")
+                .replace('<', "(")
+                .replace('>', ")")
+                .replace(['?', '"', ':'], "")
+                .replace(|c: char| c.is_ascii_control(), ""),
+        };
+
+        s.trim().to_owned()
+    }
+
+    /// Apply the profile's rules for entire names to an already sanitized name.
+    ///
+    /// Names are truncated to the length limit, preserving their extension.
+    /// Empty names, as well as `.` and `..`, are replaced by `_`.
+    pub(crate) fn finalize(&self, name: &str) -> String {
+        let mut name = name.trim().to_owned();
+
+        if self.is_windows_like() {
+            name = name.trim_end_matches(['.', ' ']).to_owned();
+        }
+
+        if self.is_windows_like() {
+            let stem = name.split('.').next().unwrap_or_default().trim_end();
+            if WINDOWS_RESERVED_NAMES
+                .iter()
+                .any(|reserved| reserved.eq_ignore_ascii_case(stem))
+            {
+                name.insert(stem.len(), '_');
+            }
+        }
+
+        let mut name = self.truncate(&name);
+
+        if self.is_windows_like() {
+            name = name.trim_end_matches(['.', ' ']).to_owned();
+        }
+
+        if name.is_empty() || name == "." || name == ".." {
+            name = "_".to_owned();
+        }
+
+        name
+    }
+
+    /// Derive a deterministic alternative for a name which collides with a sibling, such as `Workbook (2).pdf`.
+    pub(crate) fn disambiguate(&self, name: &str, n: usize) -> String {
+        let (stem, ext) = split_extension(name);
+        let suffix = format!(" ({n}){ext}");
+        let stem = truncate_to(stem, self.max_length - self.length(&suffix), |s| {
+            self.length(s)
+        });
+
+        self.finalize(&format!("{stem}{suffix}"))
+    }
+
+    /// The key under which names collide on the profile's file system.
+    pub(crate) fn collision_key(&self, name: &str) -> String {
+        let name: String = name.nfc().collect();
+        if self.is_windows_like() {
+            name.to_lowercase()
+        } else {
+            name
+        }
+    }
+
+    /// Make each name of a list of sibling names unique, appending ` (2)`, ` (3)`, ... to later duplicates.
+    pub(crate) fn disambiguate_siblings(&self, names: Vec<String>) -> Vec<String> {
+        let mut taken = HashSet::new();
+
+        names
+            .into_iter()
+            .map(|name| {
+                let mut candidate = name.clone();
+                let mut n = 1;
+                while !taken.insert(self.collision_key(&candidate)) {
+                    n += 1;
+                    candidate = self.disambiguate(&name, n);
+                }
+                candidate
+            })
+            .collect()
+    }
+
+    /// Truncate a name to the length limit, keeping a short extension intact.
+    fn truncate(&self, name: &str) -> String {
+        if self.length(name) <= self.max_length {
+            return name.to_owned();
+        }
+
+        let (stem, ext) = split_extension(name);
+        let stem = truncate_to(stem, self.max_length - self.length(ext), |s| self.length(s));
+
+        format!("{}{ext}", stem.trim_end())
+    }
+
+    /// Name length as measured by the profile's file system.
+    fn length(&self, s: &str) -> usize {
+        match self.profile {
+            SanitizeProfile::Posix => s.len(),
+            SanitizeProfile::Windows | SanitizeProfile::Exfat | SanitizeProfile::Fat32 => {
+                s.encode_utf16().count()
+            }
+        }
+    }
+
+    fn is_windows_like(&self) -> bool {
+        self.profile != SanitizeProfile::Posix
+    }
+}
+
+/// Split a name into stem and extension, including the leading dot.
+/// Overly long extensions are considered part of the stem.
+fn split_extension(name: &str) -> (&str, &str) {
+    match name.rfind('.') {
+        Some(dot) if dot > 0 && name.len() - dot <= MAX_EXTENSION_LENGTH + 1 => name.split_at(dot),
+        _ => (name, ""),
+    }
+}
+
+/// Truncate a string at a character boundary, so that its measured length does not exceed `max`.
+fn truncate_to(s: &str, max: usize, length: impl Fn(&str) -> usize) -> &str {
+    let mut end = s.len();
+    while length(&s[..end]) > max {
+        end = s[..end]
+            .char_indices()
+            .next_back()
+            .map(|(index, _)| index)
+            .unwrap_or(0);
+    }
+
+    &s[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    fn sanitizers() -> impl Strategy<Value = Sanitizer> {
+        (
+            prop::sample::select(SanitizeProfile::value_variants()),
+            prop::sample::select(Normalization::value_variants()),
+        )
+            .prop_map(|(profile, normalization)| Sanitizer::new(profile, normalization))
+    }
+
+    /// Names as rendered from templates, including reserved names, dots, spaces and long Unicode text.
+    fn names() -> impl Strategy<Value = String> {
+        prop_oneof![
+            "[a-zA-Z0-9 ._-]{0,20}",
+            "(?i)(con|prn|aux|nul|com[1-9]|lpt[1-9])[ .]{0,2}(\\.[a-z]{1,4})?",
+            "[.]{1,3}[ ]{0,2}",
+            "\\PC{0,300}(\\.[a-z0-9]{1,4})?",
+            "[äöüßéÄ😀 ]{100,300}\\.pdf",
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn finalized_names_fit_the_length_limit(sanitizer in sanitizers(), name in names()) {
+            let finalized = sanitizer.finalize(&sanitizer.sanitize_value(&name));
+            prop_assert!(!finalized.is_empty());
+            prop_assert!(sanitizer.length(&finalized) <= MAX_NAME_LENGTH);
+        }
+
+        #[test]
+        fn file_names_keep_room_for_suffixes(sanitizer in sanitizers(), name in names(), n in 2..1000usize) {
+            let sanitizer = sanitizer.for_files();
+            let finalized = sanitizer.finalize(&sanitizer.sanitize_value(&name));
+            prop_assert!(sanitizer.length(&finalized) + FILE_SUFFIX_RESERVE <= MAX_NAME_LENGTH);
+            let disambiguated = sanitizer.disambiguate(&finalized, n);
+            prop_assert!(sanitizer.length(&disambiguated) + FILE_SUFFIX_RESERVE <= MAX_NAME_LENGTH);
+        }
+
+        #[test]
+        fn finalized_names_keep_short_extensions(
+            sanitizer in sanitizers(),
+            stem in "[a-zA-Z0-9äöü😀 ]{1,400}",
+            extension in "[a-z0-9]{1,8}",
+        ) {
+            let stem = stem.trim();
+            prop_assume!(!stem.is_empty());
+            let finalized = sanitizer.finalize(&format!("{stem}.{extension}"));
+            prop_assert!(finalized.ends_with(&format!(".{extension}")), "{finalized}");
+        }
+
+        #[test]
+        fn finalized_names_are_valid(sanitizer in sanitizers(), name in names()) {
+            let finalized = sanitizer.finalize(&sanitizer.sanitize_value(&name));
+            prop_assert!(finalized != "." && finalized != "..");
+            prop_assert!(!finalized.contains(['/', '\0']));
+
+            if sanitizer.is_windows_like() {
+                prop_assert!(!finalized.ends_with(['.', ' ']), "{finalized:?}");
+            }
+            if sanitizer.is_windows_like() {
+                let stem = finalized.split('.').next().unwrap_or_default().trim_end();
+                prop_assert!(
+                    !WINDOWS_RESERVED_NAMES
+                        .iter()
+                        .any(|reserved| reserved.eq_ignore_ascii_case(stem)),
+                    "{finalized:?}"
+                );
+            }
+        }
+
+        #[test]
+        fn disambiguated_siblings_are_unique(
+            sanitizer in sanitizers(),
+            names in prop::collection::vec(
+                prop_oneof!["(?i)(a|b|workbook)( \\(2\\))?(\\.pdf)?", names()],
+                0..20,
+            ),
+        ) {
+            let names: Vec<String> = names.iter().map(|name| sanitizer.finalize(name)).collect();
+            let unique = sanitizer.disambiguate_siblings(names.clone());
+
+            prop_assert_eq!(unique.len(), names.len());
+            let keys: HashSet<String> = unique.iter().map(|name| sanitizer.collision_key(name)).collect();
+            prop_assert_eq!(keys.len(), unique.len(), "{:?}", unique);
+            prop_assert_eq!(sanitizer.disambiguate_siblings(names), unique);
+        }
+    }
+
+    #[test]
+    fn replaces_device_names() {
+        for profile in [
+            SanitizeProfile::Windows,
+            SanitizeProfile::Exfat,
+            SanitizeProfile::Fat32,
+        ] {
+            let sanitizer = Sanitizer::new(profile, Normalization::None);
+            assert_eq!(sanitizer.finalize("CON"), "CON_");
+            assert_eq!(sanitizer.finalize("nul.txt"), "nul_.txt");
+            assert_eq!(sanitizer.finalize("Console.txt"), "Console.txt");
+        }
+
+        let sanitizer = Sanitizer::new(SanitizeProfile::Posix, Normalization::None);
+        assert_eq!(sanitizer.finalize("CON"), "CON");
+    }
+
+    #[test]
+    fn replaces_relative_directory_names() {
+        for profile in SanitizeProfile::value_variants() {
+            let sanitizer = Sanitizer::new(*profile, Normalization::None);
+            assert_eq!(sanitizer.finalize("."), "_");
+            assert_eq!(sanitizer.finalize(".."), "_");
+            assert_eq!(sanitizer.finalize(" .. "), "_");
+            assert_eq!(
+                sanitizer.finalize("..."),
+                if *profile == SanitizeProfile::Posix {
+                    "..."
+                } else {
+                    "_"
+                }
+            );
+        }
+    }
+}
@@ -0,0 +1,106 @@
+use color_eyre::{eyre::WrapErr, Result};
+use tokio::sync::watch;
+use tracing::{error, warn};
+
+/// Progress of a shutdown requested by the user.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Phase {
+    Running,
+    /// No new downloads are started, while in-flight downloads are finished.
+    Stopping,
+    /// In-flight downloads are cancelled and their partial files are removed.
+    Aborting,
+}
+
+/// Graceful shutdown on Ctrl-C (or `SIGTERM` on Unix).
+///
+/// The first signal stops scheduling new downloads, letting in-flight downloads finish.
+/// The second signal aborts in-flight downloads.
+#[derive(Debug)]
+pub(crate) struct Shutdown {
+    phase: watch::Sender<Phase>,
+}
+
+impl Shutdown {
+    /// Install the signal handler.
+    pub(crate) fn listen() -> Self {
+        let (phase, _) = watch::channel(Phase::Running);
+
+        let sender = phase.clone();
+        tokio::spawn(async move {
+            if let Err(report) = signal().await {
+                error!("{report:?}");
+                return;
+            }
+            warn!("Received shutdown signal. Finishing in-flight downloads - press Ctrl-C again to abort.");
+            sender.send_replace(Phase::Stopping);
+
+            if let Err(report) = signal().await {
+                error!("{report:?}");
+                return;
+            }
+            warn!("Received second shutdown signal. Aborting in-flight downloads.");
+            sender.send_replace(Phase::Aborting);
+        });
+
+        Self { phase }
+    }
+
+    /// A shutdown which is only requested by calling `stop`, without listening for signals.
+    #[cfg(test)]
+    pub(crate) fn manual() -> Self {
+        Self {
+            phase: watch::channel(Phase::Running).0,
+        }
+    }
+
+    /// Request to stop, as on the first signal.
+    #[cfg(test)]
+    pub(crate) fn stop(&self) {
+        self.phase.send_replace(Phase::Stopping);
+    }
+
+    /// Whether new work should no longer be started.
+    pub(crate) fn is_stopping(&self) -> bool {
+        *self.phase.borrow() >= Phase::Stopping
+    }
+
+    /// Resolve once new work should no longer be started.
+    pub(crate) async fn stopping(&self) {
+        self.wait_for(Phase::Stopping).await
+    }
+
+    /// Resolve once in-flight work should be cancelled.
+    pub(crate) async fn aborting(&self) {
+        self.wait_for(Phase::Aborting).await
+    }
+
+    async fn wait_for(&self, phase: Phase) {
+        // The sender is owned by `self`, so the channel cannot close while waiting.
+        let _ = self
+            .phase
+            .subscribe()
+            .wait_for(|current| *current >= phase)
+            .await;
+    }
+}
+
+/// Resolve on Ctrl-C, or on SIGTERM on Unix.
+async fn signal() -> Result<()> {
+    #[cfg(unix)]
+    {
+        let mut terminate =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+                .wrap_err("Failed to install SIGTERM handler")?;
+
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result.wrap_err("Failed to listen for Ctrl-C"),
+            _ = terminate.recv() => Ok(()),
+        }
+    }
+
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c()
+        .await
+        .wrap_err("Failed to listen for Ctrl-C")
+}
@@ -0,0 +1,438 @@
+use std::{path::Path, process::Stdio};
+
+use color_eyre::{eyre::bail, Result};
+use futures::stream::{self, StreamExt};
+use reqwest::{header::CONTENT_LENGTH, Client};
+use tokio::process::Command;
+use tracing::{debug, info, instrument, warn, Level};
+
+use crate::{json::Asset, Context, LessonAsset, PlannedDownload};
+
+/// Warn if downloads would leave less than this share of the free space.
+const FREE_SPACE_WARNING_RATIO: f64 = 0.1;
+
+/// Parse a size such as `500MB`, `20 GB` or `1.5GiB`. Plain numbers are bytes.
+pub(crate) fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("Invalid size '{s}', expected a number such as '20GB'"))?;
+    let factor: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" => 1_000,
+        "m" | "mb" => 1_000_000,
+        "g" | "gb" => 1_000_000_000,
+        "t" | "tb" => 1_000_000_000_000,
+        "kib" => 1 << 10,
+        "mib" => 1 << 20,
+        "gib" => 1 << 30,
+        "tib" => 1 << 40,
+        unit => {
+            return Err(format!(
+                "Unknown size unit '{unit}', expected e.g. MB, GB, MiB or GiB"
+            ))
+        }
+    };
+
+    Ok((number * factor as f64) as u64)
+}
+
+/// Format a size in bytes for humans, such as `1.50 GB`.
+pub(crate) fn format_size(size: u64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+
+    if size < 1_000 {
+        return format!("{size} B");
+    }
+
+    let mut value = size as f64;
+    let mut unit = "B";
+    for next_unit in UNITS {
+        if value < 1_000.0 {
+            break;
+        }
+        value /= 1_000.0;
+        unit = next_unit;
+    }
+
+    format!("{value:.2} {unit}")
+}
+
+/// Estimate the sizes of assets whose size is not known yet.
+///
+/// Stored files are measured on disk. Other files are requested with `HEAD`,
+/// and `yt-dlp` is asked for the approximate size of embedded videos which were not downloaded before.
+#[instrument(level = Level::DEBUG, skip(downloads, context))]
+pub(crate) async fn estimate_sizes(
+    downloads: &mut [PlannedDownload],
+    context: &Context,
+    parallel: usize,
+) {
+    let client = Client::new();
+
+    let estimates: Vec<Option<u64>> = stream::iter(downloads.iter())
+        .map(|download| {
+            let client = &client;
+            async move {
+                if download.estimated_size.is_some() {
+                    return download.estimated_size;
+                }
+
+                match &download.asset {
+                    LessonAsset::File { url, .. } => match download.path() {
+                        Some(path) if download.is_stored => tokio::fs::metadata(path)
+                            .await
+                            .ok()
+                            .map(|metadata| metadata.len()),
+                        _ => {
+                            let _permit = context.throttle.acquire(url).await;
+                            head_content_length(client, url).await
+                        }
+                    },
+                    LessonAsset::Embed { url, args } if !download.is_stored => {
+                        let _permit = context.throttle.acquire(url).await;
+                        yt_dlp_filesize(&context.yt_dlp_bin, url, args).await
+                    }
+                    LessonAsset::Video { .. } | LessonAsset::Embed { .. } => None,
+                }
+            }
+        })
+        .buffered(parallel.max(1))
+        .collect()
+        .await;
+
+    for (download, estimate) in downloads.iter_mut().zip(estimates) {
+        download.estimated_size = estimate;
+    }
+}
+
+/// Request a file's size with `HEAD`.
+async fn head_content_length(client: &Client, url: &str) -> Option<u64> {
+    let response = client
+        .head(url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .inspect_err(|error| debug!("Could not determine the size of '{url}': {error}"))
+        .ok()?;
+
+    response
+        .headers()
+        .get(CONTENT_LENGTH)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()
+}
+
+/// Ask `yt-dlp` for the approximate size of an embedded video.
+async fn yt_dlp_filesize(yt_dlp_bin: &Path, embed_url: &str, args: &[String]) -> Option<u64> {
+    let output = Command::new(yt_dlp_bin)
+        .kill_on_drop(true)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .arg("--simulate")
+        .arg("--no-warnings")
+        .arg("--legacy-server-connect")
+        .arg("--add-header")
+        .arg("Referer:https://elopage.com/")
+        .arg("--print")
+        .arg("filesize_approx")
+        .args(args)
+        .arg(embed_url)
+        .output()
+        .await
+        .inspect_err(|error| debug!("Could not run yt-dlp to estimate '{embed_url}': {error}"))
+        .ok()?;
+
+    // yt-dlp prints `NA` if the size is unknown.
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .next()?
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .map(|size| size as u64)
+}
+
+/// Select lower Wistia video renditions, until the course fits into `max_total_size` bytes.
+///
+/// All videos are capped at the same rendition size, which is chosen as large as the budget allows.
+#[instrument(level = Level::DEBUG, skip(downloads))]
+pub(crate) fn fit_budget(downloads: &mut [PlannedDownload], max_total_size: u64) -> Result<()> {
+    let other_size: u64 = downloads
+        .iter()
+        .filter(|download| !matches!(download.asset, LessonAsset::Video { .. }))
+        .filter_map(|download| download.estimated_size)
+        .sum();
+
+    let videos: Vec<&[Asset]> = downloads
+        .iter()
+        .filter_map(|download| match &download.asset {
+            LessonAsset::Video { renditions, .. } => Some(renditions.as_slice()),
+            _ => None,
+        })
+        .collect();
+
+    // The size of all videos, if each is downloaded in its largest rendition of at most `cap` bytes.
+    let videos_size = |cap: u64| -> u64 {
+        videos
+            .iter()
+            .filter_map(|renditions| rendition_within(renditions, cap))
+            .map(|rendition| rendition.file_size as u64)
+            .sum()
+    };
+
+    let mut caps: Vec<u64> = videos
+        .iter()
+        .flat_map(|renditions| {
+            video_renditions(renditions).map(|rendition| rendition.file_size as u64)
+        })
+        .collect();
+    caps.sort_unstable_by(|a, b| b.cmp(a));
+    caps.dedup();
+
+    let Some(&largest) = caps.first() else {
+        if other_size > max_total_size {
+            bail!(
+                "The course ({}) does not fit into the storage budget of {}, and has no videos with lower renditions.",
+                format_size(other_size),
+                format_size(max_total_size)
+            );
+        }
+        return Ok(());
+    };
+
+    let Some(cap) = caps
+        .into_iter()
+        .find(|&cap| other_size + videos_size(cap) <= max_total_size)
+    else {
+        bail!(
+            "The course ({}) does not fit into the storage budget of {}, even with the smallest video renditions.",
+            format_size(other_size + videos_size(0)),
+            format_size(max_total_size)
+        );
+    };
+
+    if cap < largest {
+        let total_size = other_size + videos_size(cap);
+        let mut reduced = 0;
+        for download in downloads.iter_mut() {
+            if let LessonAsset::Video { size, .. } = &download.asset {
+                let previous_size = *size;
+                download.asset.select_rendition(cap);
+                if let LessonAsset::Video { size, .. } = &download.asset {
+                    if *size != previous_size {
+                        reduced += 1;
+                    }
+                    download.estimated_size = Some(*size as u64);
+                }
+            }
+        }
+
+        info!(
+            "Selected lower renditions of {reduced} video(s), to fit the course ({}) into the storage budget of {}.",
+            format_size(total_size),
+            format_size(max_total_size)
+        );
+    }
+
+    Ok(())
+}
+
+/// Select the largest video rendition of at most `max_size` bytes, or the smallest one if none is small enough,
+/// from renditions sorted largest first.
+pub(crate) fn rendition_within(renditions: &[Asset], max_size: u64) -> Option<&Asset> {
+    video_renditions(renditions)
+        .find(|rendition| rendition.file_size as u64 <= max_size)
+        .or_else(|| video_renditions(renditions).last())
+}
+
+/// The renditions which are versions of the video, rather than e.g. thumbnails, or all of them if none are.
+fn video_renditions(renditions: &[Asset]) -> impl Iterator<Item = &Asset> {
+    let has_videos = renditions.iter().any(Asset::is_video);
+    renditions
+        .iter()
+        .filter(move |rendition| !has_videos || rendition.is_video())
+}
+
+/// Ensure that the downloads fit into the free space of the file system holding the course directory.
+#[instrument(level = Level::DEBUG, skip(downloads))]
+pub(crate) fn preflight(downloads: &[PlannedDownload], course_path: &Path) -> Result<()> {
+    let pending = downloads.iter().filter(|download| !download.is_stored);
+    let unknown = pending
+        .clone()
+        .filter(|download| download.estimated_size.is_none())
+        .count();
+    let required: u64 = pending.filter_map(|download| download.estimated_size).sum();
+
+    // The course directory exists once lessons have been placed, unless the course is empty.
+    let Some(existing_path) = course_path.ancestors().find(|path| path.exists()) else {
+        return Ok(());
+    };
+    let available = match fs4::available_space(existing_path) {
+        Ok(available) => available,
+        Err(error) => {
+            warn!(
+                "Could not determine the free space at '{}': {error}",
+                existing_path.display()
+            );
+            return Ok(());
+        }
+    };
+
+    info!(
+        "About to download {} ({} free).",
+        format_size(required),
+        format_size(available)
+    );
+    if unknown > 0 {
+        warn!("The size of {unknown} file(s) is unknown, and not included.");
+    }
+
+    if required > available {
+        bail!(
+            "The downloads ({}) do not fit into the free space of {} at '{}'. Free up space, pass `--max-total-size` to download lower video renditions, or pass `--ignore-free-space` to download anyway.",
+            format_size(required),
+            format_size(available),
+            existing_path.display()
+        );
+    } else if ((available - required) as f64) < available as f64 * FREE_SPACE_WARNING_RATIO {
+        warn!(
+            "The downloads ({}) will almost fill the free space of {}.",
+            format_size(required),
+            format_size(available)
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, sync::Arc};
+
+    use super::*;
+    use crate::{template::TemplateVars, LessonDir};
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("1234"), Ok(1234));
+        assert_eq!(parse_size("500MB"), Ok(500_000_000));
+        assert_eq!(parse_size(" 20 GB "), Ok(20_000_000_000));
+        assert_eq!(parse_size("1.5GiB"), Ok(3 << 29));
+        assert_eq!(parse_size("2k"), Ok(2_000));
+        assert!(parse_size("GB").is_err());
+        assert!(parse_size("20 parsecs").is_err());
+    }
+
+    #[test]
+    fn formats_sizes() {
+        assert_eq!(format_size(0), "0 B");
+        assert_eq!(format_size(999), "999 B");
+        assert_eq!(format_size(1_500), "1.50 KB");
+        assert_eq!(format_size(1_500_000_000), "1.50 GB");
+        assert_eq!(format_size(2_000_000_000_000_000), "2000.00 TB");
+    }
+
+    fn rendition(file_size: usize, content_type: &str) -> Asset {
+        Asset {
+            url: format!("https://fast.wistia.com/{file_size}"),
+            file_size,
+            r#type: None,
+            content_type: Some(content_type.to_owned()),
+        }
+    }
+
+    fn planned(asset: LessonAsset, estimated_size: Option<u64>) -> PlannedDownload {
+        PlannedDownload {
+            lesson: Arc::new(LessonDir {
+                id: 1,
+                path: PathBuf::from("Lesson"),
+                vars: TemplateVars::default(),
+            }),
+            index: 0,
+            asset,
+            vars: TemplateVars::default(),
+            file_name: None,
+            estimated_size,
+            is_stored: false,
+            checksum: None,
+            validators: None,
+        }
+    }
+
+    fn video(sizes: &[usize]) -> PlannedDownload {
+        let renditions: Vec<_> = sizes
+            .iter()
+            .map(|&size| rendition(size, "video/mp4"))
+            .chain([rendition(1, "image/jpeg")])
+            .collect();
+        planned(
+            LessonAsset::Video {
+                url: renditions[0].url.clone(),
+                name: None,
+                size: sizes[0],
+                renditions,
+                updated_at: None,
+            },
+            Some(sizes[0] as u64),
+        )
+    }
+
+    fn video_size(download: &PlannedDownload) -> usize {
+        match &download.asset {
+            LessonAsset::Video { size, .. } => *size,
+            _ => unreachable!(),
+        }
+    }
+
+    fn downloads() -> Vec<PlannedDownload> {
+        vec![
+            video(&[1_000, 500, 100]),
+            video(&[800, 300]),
+            planned(
+                LessonAsset::File {
+                    url: "https://cdn.example.com/workbook.pdf".to_owned(),
+                    name: None,
+                    linked: false,
+                    updated_at: None,
+                },
+                Some(200),
+            ),
+        ]
+    }
+
+    #[test]
+    fn keeps_largest_renditions_within_budget() {
+        let mut downloads = downloads();
+        fit_budget(&mut downloads, 2_000).unwrap();
+        assert_eq!(video_size(&downloads[0]), 1_000);
+        assert_eq!(video_size(&downloads[1]), 800);
+    }
+
+    #[test]
+    fn caps_renditions_to_fit_budget() {
+        let mut downloads = downloads();
+        fit_budget(&mut downloads, 1_100).unwrap();
+        // Both videos are capped at 500 bytes, which is the largest cap that fits.
+        assert_eq!(video_size(&downloads[0]), 500);
+        assert_eq!(video_size(&downloads[1]), 300);
+        assert_eq!(downloads[0].estimated_size, Some(500));
+    }
+
+    #[test]
+    fn never_selects_thumbnails() {
+        let mut fitted = downloads();
+        fit_budget(&mut fitted, 600).unwrap();
+        assert_eq!(video_size(&fitted[0]), 100);
+        assert_eq!(video_size(&fitted[1]), 300);
+
+        assert!(fit_budget(&mut downloads(), 500).is_err());
+    }
+}
@@ -0,0 +1,213 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use color_eyre::{eyre::WrapErr, Result};
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use tracing::{debug, instrument, Level};
+
+use crate::Id;
+
+/// Name of the state file, stored in the course directory.
+pub(crate) const STATE_FILE_NAME: &str = ".elopage-dl.json";
+
+/// Course state recorded on every run, allowing later runs to recognize local files by elopage IDs.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub(crate) struct State {
+    /// Course metadata.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub course: Option<CourseState>,
+    /// Categories and lessons, by ID.
+    #[serde(default)]
+    pub items: BTreeMap<Id, ItemState>,
+    /// Directory path of the fallback category of orphaned items, relative to the course directory, if there was one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unsorted: Option<PathBuf>,
+}
+
+/// Metadata describing the course stored in a course directory.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct CourseState {
+    pub id: Id,
+    pub name: String,
+    pub seller: String,
+    /// End of the license or course access, as RFC 3339 timestamp.
+    /// The offline cache must be deleted when the license expires.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub license_expires: Option<String>,
+}
+
+/// A category or lesson, as stored on disk.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct ItemState {
+    pub name: String,
+    pub is_category: bool,
+    /// Directory path, relative to the course directory.
+    pub path: PathBuf,
+    /// Assets of a lesson, in order of appearance.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub assets: Vec<AssetState>,
+}
+
+/// An asset of a lesson.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub(crate) struct AssetState {
+    /// Download or embed URL.
+    pub source: String,
+    /// File name within the lesson directory. `None` for embedded videos, which are named by `yt-dlp`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_name: Option<String>,
+    /// File name within the lesson directory which `yt-dlp` chose for an embedded video, once downloaded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub embed_file_name: Option<String>,
+    /// Size in bytes, if known before downloading.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    /// Whether the asset has not been downloaded completely, such as after an interrupted run.
+    /// Pending assets are downloaded again in mirror mode.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub pending: bool,
+    /// Length and hash of the downloaded file, to verify the offline cache.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<Checksum>,
+    /// HTTP validators of the downloaded file, to check whether it was updated in place with a conditional request.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub validators: Option<Validators>,
+}
+
+/// Length and SHA-256 hash of a downloaded file.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub(crate) struct Checksum {
+    pub length: u64,
+    /// Lowercase hex digest.
+    pub sha256: String,
+}
+
+/// The `ETag` and `Last-Modified` headers a file was served with.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub(crate) struct Validators {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<String>,
+}
+
+impl Validators {
+    /// Whether a response's validators identify the same version of the file as these stored ones.
+    ///
+    /// ETags are compared weakly, and `Last-Modified` only if either side lacks an ETag.
+    pub(crate) fn matches(&self, response: &Validators) -> bool {
+        let weak = |etag: &str| etag.trim().trim_start_matches("W/").to_owned();
+
+        match (&self.etag, &response.etag) {
+            (Some(stored), Some(served)) => weak(stored) == weak(served),
+            _ => self.last_modified.is_some() && self.last_modified == response.last_modified,
+        }
+    }
+}
+
+impl AssetState {
+    /// Key identifying the asset within its lesson across runs: the file name, or the URL of embedded videos.
+    pub(crate) fn key(&self) -> &str {
+        self.file_name.as_deref().unwrap_or(&self.source)
+    }
+
+    /// Whether the asset is unchanged since it was recorded as `previous`.
+    ///
+    /// Query strings are ignored when comparing sources, as download URLs might carry expiring signatures.
+    pub(crate) fn is_unchanged(&self, previous: &AssetState) -> bool {
+        without_query(&self.source) == without_query(&previous.source) && self.size == previous.size
+    }
+}
+
+/// A URL without its query string, which might carry an expiring signature.
+pub(crate) fn without_query(url: &str) -> &str {
+    url.split_once('?').map_or(url, |(url, _)| url)
+}
+
+impl State {
+    /// Load the state file from the course directory, or start with an empty state if there is none.
+    #[instrument(level = Level::DEBUG)]
+    pub(crate) async fn load(course_path: &Path) -> Result<Self> {
+        let state_path = course_path.join(STATE_FILE_NAME);
+
+        match fs::read(&state_path).await {
+            Ok(json) => serde_json::from_slice(&json)
+                .wrap_err_with(|| format!("Failed to parse state file '{}'", state_path.display())),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+                debug!("No state file at '{}'.", state_path.display());
+                Ok(Self::default())
+            }
+            Err(error) => Err(error)
+                .wrap_err_with(|| format!("Failed to read state file '{}'", state_path.display())),
+        }
+    }
+
+    /// Write the state file to the course directory.
+    ///
+    /// The state is written to a temporary file first, then moved into place,
+    /// so that an interrupted write cannot leave a truncated state file behind.
+    #[instrument(level = Level::DEBUG, skip(self))]
+    pub(crate) async fn save(&self, course_path: &Path) -> Result<()> {
+        let state_path = course_path.join(STATE_FILE_NAME);
+        let temporary_path = course_path.join(format!("{STATE_FILE_NAME}.tmp"));
+
+        fs::create_dir_all(course_path)
+            .await
+            .wrap_err("Failed to create course path")?;
+        fs::write(&temporary_path, serde_json::to_vec_pretty(self)?)
+            .await
+            .wrap_err("Failed to write state file")?;
+        fs::rename(&temporary_path, &state_path)
+            .await
+            .wrap_err("Failed to move state file into place")?;
+
+        Ok(())
+    }
+
+    /// Rewrite recorded paths after the directory at `from` was moved to `to`, including the paths of nested items.
+    pub(crate) fn relocate(&mut self, from: &Path, to: &Path) {
+        for path in self
+            .items
+            .values_mut()
+            .map(|item| &mut item.path)
+            .chain(self.unsorted.as_mut())
+        {
+            if let Ok(nested) = path.strip_prefix(from) {
+                *path = to.join(nested);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validators(etag: Option<&str>, last_modified: Option<&str>) -> Validators {
+        Validators {
+            etag: etag.map(str::to_owned),
+            last_modified: last_modified.map(str::to_owned),
+        }
+    }
+
+    #[test]
+    fn matches_validators_of_unchanged_files() {
+        const MONDAY: &str = "Mon, 05 May 2025 10:00:00 GMT";
+        const TUESDAY: &str = "Tue, 06 May 2025 10:00:00 GMT";
+
+        let stored = validators(Some("\"abc\""), Some(MONDAY));
+        assert!(stored.matches(&validators(Some("\"abc\""), Some(TUESDAY))));
+        assert!(stored.matches(&validators(Some("W/\"abc\""), None)));
+        assert!(!stored.matches(&validators(Some("\"def\""), Some(MONDAY))));
+        assert!(stored.matches(&validators(None, Some(MONDAY))));
+        assert!(!stored.matches(&validators(None, None)));
+
+        let stored = validators(None, Some(MONDAY));
+        assert!(stored.matches(&validators(Some("\"abc\""), Some(MONDAY))));
+        assert!(!stored.matches(&validators(None, Some(TUESDAY))));
+        assert!(!validators(None, None).matches(&validators(None, None)));
+    }
+}
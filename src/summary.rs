@@ -0,0 +1,130 @@
+use std::{path::PathBuf, sync::Mutex};
+
+use tracing::{info, warn};
+
+use crate::{lock, Id};
+
+/// Notable events collected while processing a course, reported once all downloads have finished.
+#[derive(Debug, Default)]
+pub(crate) struct Summary {
+    inner: Mutex<SummaryData>,
+}
+
+#[derive(Debug, Default)]
+struct SummaryData {
+    orphaned_lessons: Vec<(Id, String)>,
+    parent_cycles: Vec<Vec<Id>>,
+    renamed_assets: Vec<(PathBuf, PathBuf)>,
+    moved_items: Vec<(PathBuf, PathBuf)>,
+    removed_items: Vec<(String, PathBuf)>,
+    failed_downloads: Vec<(PathBuf, String)>,
+}
+
+impl Summary {
+    /// Record a lesson which could not be placed into the module tree.
+    pub(crate) fn orphaned_lesson(&self, id: Id, name: impl Into<String>) {
+        self.lock().orphaned_lessons.push((id, name.into()));
+    }
+
+    /// Record a cycle of `parent_id` references, listed in parent order.
+    pub(crate) fn parent_cycle(&self, cycle: Vec<Id>) {
+        self.lock().parent_cycles.push(cycle);
+    }
+
+    /// Record an asset stored under a different name, because another asset of the same lesson had the same name.
+    pub(crate) fn renamed_asset(&self, planned: PathBuf, stored: PathBuf) {
+        self.lock().renamed_assets.push((planned, stored));
+    }
+
+    /// Record a lesson or category directory which was moved after the item was renamed or reordered.
+    pub(crate) fn moved_item(&self, from: PathBuf, to: PathBuf) {
+        self.lock().moved_items.push((from, to));
+    }
+
+    /// Record a lesson or category which was removed from the course.
+    pub(crate) fn removed_item(&self, name: String, path: PathBuf) {
+        self.lock().removed_items.push((name, path));
+    }
+
+    /// Record a download which failed without failing the run, such as one which was not as long as expected.
+    pub(crate) fn failed_download(&self, path: PathBuf, reason: String) {
+        self.lock().failed_downloads.push((path, reason));
+    }
+
+    /// Log the run summary.
+    pub(crate) fn report(&self) {
+        let data = self.lock();
+
+        for cycle in &data.parent_cycles {
+            warn!(
+                "Lessons list contained a `parent_id` cycle: {}",
+                cycle
+                    .iter()
+                    .map(Id::to_string)
+                    .collect::<Vec<_>>()
+                    .join(" -> ")
+            );
+        }
+
+        if !data.orphaned_lessons.is_empty() {
+            warn!(
+                "{} orphaned lesson(s) were downloaded into the '{}' folder:",
+                data.orphaned_lessons.len(),
+                crate::UNSORTED_CATEGORY_NAME
+            );
+            for (id, name) in &data.orphaned_lessons {
+                warn!("  - Lesson ID '{id}': {name}");
+            }
+        }
+
+        if !data.renamed_assets.is_empty() {
+            warn!(
+                "{} asset(s) were renamed to avoid overwriting another asset of the same name:",
+                data.renamed_assets.len()
+            );
+            for (planned, stored) in &data.renamed_assets {
+                warn!(
+                    "  - '{}' stored as '{}'",
+                    planned.display(),
+                    stored.display()
+                );
+            }
+        }
+
+        if !data.moved_items.is_empty() {
+            info!(
+                "{} renamed or reordered item(s) were moved:",
+                data.moved_items.len()
+            );
+            for (from, to) in &data.moved_items {
+                info!("  - '{}' -> '{}'", from.display(), to.display());
+            }
+        }
+
+        if !data.removed_items.is_empty() {
+            warn!(
+                "{} item(s) were removed from the course:",
+                data.removed_items.len()
+            );
+            for (name, path) in &data.removed_items {
+                warn!("  - {name} ('{}')", path.display());
+            }
+        }
+
+        if !data.failed_downloads.is_empty() {
+            warn!(
+                "{} file(s) failed to download, and are downloaded again by the next run:",
+                data.failed_downloads.len()
+            );
+            for (path, reason) in &data.failed_downloads {
+                warn!("  - '{}': {reason}", path.display());
+            }
+        }
+
+        info!("Finished processing course.");
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, SummaryData> {
+        lock(&self.inner)
+    }
+}
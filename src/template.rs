@@ -0,0 +1,424 @@
+use std::{collections::HashMap, fmt::Display, str::FromStr};
+
+use color_eyre::{
+    eyre::{bail, eyre},
+    Report, Result,
+};
+
+use crate::{args::DownloadArgs, sanitize::Sanitizer};
+
+/// A variable which can be referenced as `{variable}` or `{variable:0N}` in a path template.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum Variable {
+    /// Name of the category, lesson or asset.
+    Name,
+    /// Asset name without its extension.
+    Stem,
+    /// Asset name extension, without the leading dot.
+    Ext,
+    /// 1-based index of the category or lesson among its siblings,
+    /// or of the asset in order of appearance within its lesson.
+    Index,
+    /// elopage's `position` property of the category or lesson.
+    Position,
+    /// elopage ID of the category or lesson.
+    Id,
+    /// 1-based depth of the category or lesson in the module tree.
+    Depth,
+    /// Name of the parent category, or empty for root items.
+    Parent,
+    /// Name of the lesson an asset belongs to.
+    Lesson,
+    /// elopage ID of the lesson an asset belongs to.
+    LessonId,
+    /// Seller username.
+    Seller,
+    /// Seller full name.
+    SellerName,
+    /// Course (product) name.
+    Course,
+    /// Course session ID.
+    CourseId,
+}
+
+impl Variable {
+    const ALL: [Variable; 14] = [
+        Variable::Name,
+        Variable::Stem,
+        Variable::Ext,
+        Variable::Index,
+        Variable::Position,
+        Variable::Id,
+        Variable::Depth,
+        Variable::Parent,
+        Variable::Lesson,
+        Variable::LessonId,
+        Variable::Seller,
+        Variable::SellerName,
+        Variable::Course,
+        Variable::CourseId,
+    ];
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Variable::Name => "name",
+            Variable::Stem => "stem",
+            Variable::Ext => "ext",
+            Variable::Index => "index",
+            Variable::Position => "position",
+            Variable::Id => "id",
+            Variable::Depth => "depth",
+            Variable::Parent => "parent",
+            Variable::Lesson => "lesson",
+            Variable::LessonId => "lesson_id",
+            Variable::Seller => "seller",
+            Variable::SellerName => "seller_name",
+            Variable::Course => "course",
+            Variable::CourseId => "course_id",
+        }
+    }
+}
+
+impl Display for Variable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Variable {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Variable::ALL
+            .into_iter()
+            .find(|variable| variable.as_str() == s)
+            .ok_or_else(|| eyre!("Unknown template variable '{{{s}}}'"))
+    }
+}
+
+/// The kind of path a template describes, which determines the variables available to it.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum TemplateKind {
+    Course,
+    Category,
+    Lesson,
+    Asset,
+}
+
+impl TemplateKind {
+    fn variables(self) -> &'static [Variable] {
+        use Variable::*;
+
+        match self {
+            TemplateKind::Course => &[Seller, SellerName, Course, CourseId],
+            TemplateKind::Category | TemplateKind::Lesson => &[
+                Name, Index, Position, Id, Depth, Parent, Seller, SellerName, Course, CourseId,
+            ],
+            TemplateKind::Asset => &[
+                Name, Stem, Ext, Index, Lesson, LessonId, Parent, Seller, SellerName, Course,
+                CourseId,
+            ],
+        }
+    }
+}
+
+impl Display for TemplateKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            TemplateKind::Course => "course",
+            TemplateKind::Category => "category",
+            TemplateKind::Lesson => "lesson",
+            TemplateKind::Asset => "asset",
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Segment {
+    Literal(String),
+    Variable {
+        variable: Variable,
+        width: Option<usize>,
+    },
+}
+
+/// A parsed path template, such as `{index} {name}`.
+///
+/// Variable values are made path-safe before they are substituted, while literal template text is kept as is.
+/// Thus, a `/` in the template text of a course, category or lesson template creates a subdirectory.
+/// Asset files are stored directly in their lesson directory, so asset templates cannot contain `/`.
+/// Each rendered path component is then checked against the sanitizer profile's rules for entire names.
+#[derive(Clone, Debug)]
+pub(crate) struct Template {
+    segments: Vec<Segment>,
+}
+
+impl Template {
+    /// Parse a template, ensuring it only references variables available to the given kind of path.
+    pub(crate) fn parse(template: &str, kind: TemplateKind) -> Result<Self> {
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+        let mut chars = template.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut placeholder = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => placeholder.push(c),
+                            None => bail!("Unclosed '{{' in {kind} template '{template}'"),
+                        }
+                    }
+
+                    let (name, width) = match placeholder.split_once(':') {
+                        Some((name, spec)) => (
+                            name,
+                            Some(spec.parse::<usize>().map_err(|_| {
+                                eyre!("Invalid width '{spec}' for '{{{name}}}' in {kind} template '{template}', expected a number such as '03'")
+                            })?),
+                        ),
+                        None => (placeholder.as_str(), None),
+                    };
+
+                    let variable: Variable = name.parse()?;
+                    if !kind.variables().contains(&variable) {
+                        bail!(
+                            "Variable '{{{variable}}}' is not available in {kind} templates. Available variables: {}",
+                            kind.variables()
+                                .iter()
+                                .map(|variable| format!("{{{variable}}}"))
+                                .collect::<Vec<_>>()
+                                .join(", ")
+                        );
+                    }
+
+                    if !literal.is_empty() {
+                        segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                    }
+                    segments.push(Segment::Variable { variable, width });
+                }
+                '}' => bail!("Unmatched '}}' in {kind} template '{template}'"),
+                '/' if matches!(kind, TemplateKind::Asset) => {
+                    bail!("Asset template '{template}' must not contain '/', as files are stored in their lesson directory")
+                }
+                c => literal.push(c),
+            }
+        }
+
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+
+        Ok(Self { segments })
+    }
+
+    /// Render the template to a path.
+    pub(crate) fn render(&self, vars: &TemplateVars, sanitizer: &Sanitizer) -> String {
+        self.render_escaped(vars, sanitizer, |s| s.to_owned())
+            .split('/')
+            .map(|component| sanitizer.finalize(component))
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    /// Render the template to a `yt-dlp` output template.
+    ///
+    /// `%` in template text and values is escaped, while raw values (`yt-dlp` fields) are passed through.
+    pub(crate) fn render_yt_dlp(&self, vars: &TemplateVars, sanitizer: &Sanitizer) -> String {
+        self.render_escaped(vars, sanitizer, |s| s.replace('%', "%%"))
+    }
+
+    fn render_escaped(
+        &self,
+        vars: &TemplateVars,
+        sanitizer: &Sanitizer,
+        escape: impl Fn(&str) -> String,
+    ) -> String {
+        let mut rendered = String::new();
+
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(literal) => rendered.push_str(&escape(literal)),
+                Segment::Variable { variable, width } => match vars.0.get(variable) {
+                    Some(Value::Text(text)) => {
+                        rendered.push_str(&escape(&sanitizer.sanitize_value(text)))
+                    }
+                    Some(Value::Raw(raw)) => rendered.push_str(raw),
+                    Some(Value::Number {
+                        value,
+                        width: default_width,
+                    }) => rendered.push_str(&format!(
+                        "{value:0width$}",
+                        width = width.unwrap_or(*default_width)
+                    )),
+                    // Variables are validated on parsing; absent values (such as the parent of a root item) render empty.
+                    None => {}
+                },
+            }
+        }
+
+        rendered
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Value {
+    Text(String),
+    /// Value substituted without sanitizing or escaping.
+    Raw(String),
+    Number {
+        value: usize,
+        width: usize,
+    },
+}
+
+/// Values for template variables.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct TemplateVars(HashMap<Variable, Value>);
+
+impl TemplateVars {
+    pub(crate) fn text(mut self, variable: Variable, value: impl Into<String>) -> Self {
+        self.0.insert(variable, Value::Text(value.into()));
+        self
+    }
+
+    pub(crate) fn raw(mut self, variable: Variable, value: impl Into<String>) -> Self {
+        self.0.insert(variable, Value::Raw(value.into()));
+        self
+    }
+
+    /// Set a numeric value, zero-padded to `width` unless the template specifies a width.
+    pub(crate) fn number(mut self, variable: Variable, value: usize, width: usize) -> Self {
+        self.0.insert(variable, Value::Number { value, width });
+        self
+    }
+}
+
+/// Zero-padding width for an index among `count` siblings - at least two digits, as in `01`.
+pub(crate) fn index_width(count: usize) -> usize {
+    count.to_string().len().max(2)
+}
+
+/// The parsed path templates for all kinds of paths.
+#[derive(Clone, Debug)]
+pub(crate) struct Templates {
+    pub course: Template,
+    pub category: Template,
+    pub lesson: Template,
+    pub asset: Template,
+}
+
+impl Templates {
+    pub(crate) fn from_args(args: &DownloadArgs) -> Result<Self> {
+        Ok(Self {
+            course: Template::parse(&args.course_template, TemplateKind::Course)?,
+            category: Template::parse(&args.category_template, TemplateKind::Category)?,
+            lesson: Template::parse(&args.lesson_template, TemplateKind::Lesson)?,
+            asset: Template::parse(
+                &if args.number_assets {
+                    format!("{{index}} {}", args.asset_template)
+                } else {
+                    args.asset_template.clone()
+                },
+                TemplateKind::Asset,
+            )?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sanitize::{Normalization, SanitizeProfile};
+
+    fn render(template: &str, kind: TemplateKind, vars: &TemplateVars) -> String {
+        Template::parse(template, kind).unwrap().render(
+            vars,
+            &Sanitizer::new(SanitizeProfile::Exfat, Normalization::None),
+        )
+    }
+
+    #[test]
+    fn pads_numbers() {
+        let vars = TemplateVars::default()
+            .text(Variable::Name, "Intro")
+            .number(Variable::Index, 7, index_width(12));
+
+        assert_eq!(
+            render("{index} {name}", TemplateKind::Lesson, &vars),
+            "07 Intro"
+        );
+        assert_eq!(
+            render("{index:03} - {name}", TemplateKind::Lesson, &vars),
+            "007 - Intro"
+        );
+        assert_eq!(render("{index:1}", TemplateKind::Lesson, &vars), "7");
+        assert_eq!(index_width(9), 2);
+        assert_eq!(index_width(120), 3);
+    }
+
+    #[test]
+    fn renders_literal_slashes_as_directories() {
+        let vars = TemplateVars::default()
+            .text(Variable::Course, "Cooking / Baking")
+            .text(Variable::Seller, "chef");
+
+        // Slashes in values never create directories.
+        assert_eq!(
+            render("Courses/{seller}/{course}", TemplateKind::Course, &vars),
+            "Courses/chef/Cooking - Baking"
+        );
+        // Absent values render empty, and empty components are replaced.
+        assert_eq!(
+            render("{{{course_id}}}/", TemplateKind::Course, &vars),
+            "{}/_"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_templates() {
+        for (template, kind) in [
+            ("{unknown}", TemplateKind::Lesson),
+            ("{lesson}", TemplateKind::Lesson),
+            ("{id}", TemplateKind::Asset),
+            ("{name", TemplateKind::Lesson),
+            ("name}", TemplateKind::Lesson),
+            ("{index:three}", TemplateKind::Lesson),
+            ("{lesson}/{name}", TemplateKind::Asset),
+        ] {
+            assert!(
+                Template::parse(template, kind).is_err(),
+                "{kind} template '{template}'"
+            );
+        }
+
+        assert!(Template::parse("{{literal}} {name}", TemplateKind::Asset).is_ok());
+    }
+
+    #[test]
+    fn escapes_yt_dlp_templates() {
+        let vars = TemplateVars::default()
+            .text(Variable::Lesson, "100% Basics")
+            .raw(Variable::Name, "%(title)s.%(ext)s");
+
+        assert_eq!(
+            Template::parse("{lesson} - {name}", TemplateKind::Asset)
+                .unwrap()
+                .render_yt_dlp(
+                    &vars,
+                    &Sanitizer::new(SanitizeProfile::Exfat, Normalization::None)
+                ),
+            "100%% Basics - %(title)s.%(ext)s"
+        );
+    }
+}
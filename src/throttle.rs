@@ -0,0 +1,439 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, MutexGuard},
+    time::Duration,
+};
+
+use clap::ValueEnum;
+use color_eyre::Result;
+use reqwest::{header::RETRY_AFTER, RequestBuilder, Response, StatusCode};
+use tokio::{sync::Notify, time::Instant};
+use tracing::{info, warn};
+
+use crate::lock;
+
+/// Number of attempts of a request throttled by its provider, in adaptive mode.
+const MAX_ATTEMPTS: u32 = 5;
+
+/// Minimum time between halving a provider's concurrency, so that simultaneous throttled requests halve it once.
+const THROTTLE_DEBOUNCE: Duration = Duration::from_secs(5);
+
+/// Time without throttling after which a provider's concurrency is raised by one.
+const RAMP_UP_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A content provider, whose rate limits apply across all of its hosts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, ValueEnum)]
+pub(crate) enum Provider {
+    /// The elopage API and file storage.
+    Elopage,
+    /// Wistia video CDN.
+    Wistia,
+    /// Vimeo, as downloaded by `yt-dlp`.
+    Vimeo,
+    /// YouTube, as downloaded by `yt-dlp`.
+    Youtube,
+    /// Any other host.
+    Other,
+}
+
+impl Provider {
+    const ALL: [Provider; 5] = [
+        Provider::Elopage,
+        Provider::Wistia,
+        Provider::Vimeo,
+        Provider::Youtube,
+        Provider::Other,
+    ];
+
+    /// Determine the provider serving a URL.
+    pub(crate) fn of(url: &str) -> Self {
+        let Some(host) = reqwest::Url::parse(url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_ascii_lowercase))
+        else {
+            return Provider::Other;
+        };
+
+        let is_domain = |domain: &str| host == domain || host.ends_with(&format!(".{domain}"));
+
+        if is_domain("elopage.com") {
+            Provider::Elopage
+        } else if ["wistia.com", "wistia.net", "wistia.io"]
+            .into_iter()
+            .any(is_domain)
+        {
+            Provider::Wistia
+        } else if ["vimeo.com", "vimeocdn.com"].into_iter().any(is_domain) {
+            Provider::Vimeo
+        } else if [
+            "youtube.com",
+            "youtu.be",
+            "googlevideo.com",
+            "youtube-nocookie.com",
+        ]
+        .into_iter()
+        .any(is_domain)
+        {
+            Provider::Youtube
+        } else {
+            Provider::Other
+        }
+    }
+}
+
+/// Parse a per-provider concurrency limit, such as `vimeo=2`.
+pub(crate) fn parse_provider_limit(s: &str) -> Result<(Provider, usize), String> {
+    let (provider, limit) = s
+        .split_once('=')
+        .ok_or_else(|| format!("Invalid provider limit '{s}', expected e.g. 'vimeo=2'"))?;
+
+    let provider = Provider::from_str(provider.trim(), true)?;
+    let limit = limit
+        .trim()
+        .parse::<usize>()
+        .ok()
+        .filter(|&limit| limit > 0)
+        .ok_or_else(|| format!("Invalid limit '{limit}', expected a positive number"))?;
+
+    Ok((provider, limit))
+}
+
+/// Concurrency limits per provider, optionally adapting to throttling responses.
+#[derive(Debug)]
+pub(crate) struct Throttle {
+    limits: HashMap<Provider, ProviderLimit>,
+    adaptive: bool,
+}
+
+#[derive(Debug)]
+struct ProviderLimit {
+    state: Mutex<LimitState>,
+    notify: Notify,
+}
+
+#[derive(Debug)]
+struct LimitState {
+    /// Configured concurrency.
+    max: usize,
+    /// Current concurrency, below `max` after throttling in adaptive mode.
+    current: usize,
+    in_flight: usize,
+    last_change: Instant,
+}
+
+/// A slot for a request to a provider, released on drop.
+#[derive(Debug)]
+pub(crate) struct Permit<'a> {
+    limit: &'a ProviderLimit,
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.limit.state().in_flight -= 1;
+        self.limit.notify.notify_waiters();
+    }
+}
+
+impl Throttle {
+    /// Limit each provider to `parallel` concurrent requests, unless limited further by `provider_limits`.
+    pub(crate) fn new(
+        parallel: usize,
+        provider_limits: &[(Provider, usize)],
+        adaptive: bool,
+    ) -> Self {
+        let limits = Provider::ALL
+            .into_iter()
+            .map(|provider| {
+                let max = provider_limits
+                    .iter()
+                    .rev()
+                    .find(|(limited, _)| *limited == provider)
+                    .map_or(parallel, |(_, limit)| (*limit).min(parallel));
+
+                (
+                    provider,
+                    ProviderLimit {
+                        state: Mutex::new(LimitState {
+                            max,
+                            current: max,
+                            in_flight: 0,
+                            last_change: Instant::now(),
+                        }),
+                        notify: Notify::new(),
+                    },
+                )
+            })
+            .collect();
+
+        Self { limits, adaptive }
+    }
+
+    /// Wait for a free slot for a request to the provider serving `url`.
+    pub(crate) async fn acquire(&self, url: &str) -> Permit<'_> {
+        let limit = self.limit(url);
+
+        loop {
+            let notified = {
+                let mut state = limit.state();
+                if state.in_flight < state.current {
+                    state.in_flight += 1;
+                    return Permit { limit };
+                }
+
+                // Registered before the lock is released, so that no release is missed.
+                limit.notify.notified()
+            };
+
+            notified.await;
+        }
+    }
+
+    /// Send a request to `url`, built by `request`, in a slot of its provider.
+    ///
+    /// In adaptive mode, requests which are throttled by the provider are retried after a backoff.
+    /// Error statuses are turned into errors. The slot is held until the returned permit is dropped.
+    pub(crate) async fn send(
+        &self,
+        url: &str,
+        request: impl Fn() -> RequestBuilder,
+    ) -> Result<(Response, Permit<'_>)> {
+        let (response, permit) = self.send_retrying(url, request, true).await?;
+
+        Ok((
+            response,
+            permit.expect("a slot is acquired for each attempt"),
+        ))
+    }
+
+    /// Send a request as `send` does, in a slot which the caller holds already, such as for one of several ranges of a file.
+    pub(crate) async fn send_in_slot(
+        &self,
+        url: &str,
+        request: impl Fn() -> RequestBuilder,
+    ) -> Result<Response> {
+        let (response, _) = self.send_retrying(url, request, false).await?;
+
+        Ok(response)
+    }
+
+    async fn send_retrying(
+        &self,
+        url: &str,
+        request: impl Fn() -> RequestBuilder,
+        acquire: bool,
+    ) -> Result<(Response, Option<Permit<'_>>)> {
+        let mut attempt = 1;
+        let (response, permit) = loop {
+            let permit = if acquire {
+                Some(self.acquire(url).await)
+            } else {
+                None
+            };
+            let response = request().send().await?;
+
+            if is_throttling(response.status()) && self.adaptive && attempt < MAX_ATTEMPTS {
+                self.throttled(url);
+                drop(permit);
+
+                let delay =
+                    retry_after(&response).unwrap_or(Duration::from_secs(2u64.pow(attempt)));
+                warn!(
+                    "Request to '{url}' was throttled ({}). Retrying in {}...",
+                    response.status(),
+                    humantime::format_duration(delay)
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+                continue;
+            }
+
+            break (response.error_for_status()?, permit);
+        };
+        self.succeeded(url);
+
+        Ok((response, permit))
+    }
+
+    /// Take a free slot for a request to the provider serving `url`, if there is one, without waiting.
+    pub(crate) fn try_acquire(&self, url: &str) -> Option<Permit<'_>> {
+        let limit = self.limit(url);
+        let mut state = limit.state();
+        (state.in_flight < state.current).then(|| {
+            state.in_flight += 1;
+            Permit { limit }
+        })
+    }
+
+    /// In adaptive mode, halve the concurrency of the provider serving `url`, after it throttled a request.
+    pub(crate) fn throttled(&self, url: &str) {
+        if !self.adaptive {
+            return;
+        }
+
+        let mut state = self.limit(url).state();
+        if state.last_change.elapsed() >= THROTTLE_DEBOUNCE || state.current == state.max {
+            let current = (state.current / 2).max(1);
+            if current != state.current {
+                warn!(
+                    "{:?} is throttling downloads. Reducing its concurrency to {current}.",
+                    Provider::of(url)
+                );
+            }
+            state.current = current;
+            state.last_change = Instant::now();
+        }
+    }
+
+    /// In adaptive mode, slowly raise the concurrency of the provider serving `url` back up, after a successful request.
+    pub(crate) fn succeeded(&self, url: &str) {
+        if !self.adaptive {
+            return;
+        }
+
+        let limit = self.limit(url);
+        let mut state = limit.state();
+        if state.current < state.max && state.last_change.elapsed() >= RAMP_UP_INTERVAL {
+            state.current += 1;
+            state.last_change = Instant::now();
+            info!(
+                "Raising the concurrency of {:?} to {}.",
+                Provider::of(url),
+                state.current
+            );
+            drop(state);
+            limit.notify.notify_waiters();
+        }
+    }
+
+    fn limit(&self, url: &str) -> &ProviderLimit {
+        &self.limits[&Provider::of(url)]
+    }
+}
+
+impl ProviderLimit {
+    fn state(&self) -> MutexGuard<'_, LimitState> {
+        lock(&self.state)
+    }
+}
+
+/// Whether a response status indicates that the provider throttles requests.
+fn is_throttling(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE
+    )
+}
+
+/// The delay requested by a throttling response's `Retry-After` header, in seconds.
+fn retry_after(response: &Response) -> Option<Duration> {
+    response
+        .headers()
+        .get(RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+        .map(Duration::from_secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::advance;
+
+    use super::*;
+
+    const VIMEO: &str = "https://player.vimeo.com/video/1";
+
+    fn current(throttle: &Throttle, url: &str) -> usize {
+        throttle.limit(url).state().current
+    }
+
+    #[test]
+    fn determines_providers() {
+        assert_eq!(
+            Provider::of("https://api.elopage.com/v1/courses"),
+            Provider::Elopage
+        );
+        assert_eq!(
+            Provider::of("https://embed-ssl.wistia.com/deliveries/1.bin"),
+            Provider::Wistia
+        );
+        assert_eq!(
+            Provider::of("https://VOD-ADAPTIVE.akamaized.net.vimeocdn.com/1"),
+            Provider::Vimeo
+        );
+        assert_eq!(Provider::of("https://youtu.be/1"), Provider::Youtube);
+        assert_eq!(Provider::of("https://notelopage.com/1"), Provider::Other);
+        assert_eq!(Provider::of("not a url"), Provider::Other);
+    }
+
+    #[test]
+    fn parses_provider_limits() {
+        assert_eq!(parse_provider_limit("vimeo=2"), Ok((Provider::Vimeo, 2)));
+        assert_eq!(
+            parse_provider_limit(" YouTube = 1 "),
+            Ok((Provider::Youtube, 1))
+        );
+        assert!(parse_provider_limit("vimeo").is_err());
+        assert!(parse_provider_limit("vimeo=0").is_err());
+        assert!(parse_provider_limit("dailymotion=2").is_err());
+    }
+
+    #[test]
+    fn caps_provider_limits_at_parallel() {
+        let throttle = Throttle::new(4, &[(Provider::Vimeo, 8), (Provider::Wistia, 2)], false);
+        assert_eq!(current(&throttle, VIMEO), 4);
+        assert_eq!(current(&throttle, "https://fast.wistia.net/1"), 2);
+
+        let _permits: Vec<_> = (0..4)
+            .map(|_| throttle.try_acquire(VIMEO).unwrap())
+            .collect();
+        assert!(throttle.try_acquire(VIMEO).is_none());
+        assert!(throttle.try_acquire("https://example.com/").is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn halves_concurrency_once_per_burst_of_throttling() {
+        let throttle = Throttle::new(8, &[], true);
+
+        throttle.throttled(VIMEO);
+        throttle.throttled(VIMEO);
+        assert_eq!(current(&throttle, VIMEO), 4);
+
+        advance(THROTTLE_DEBOUNCE).await;
+        throttle.throttled(VIMEO);
+        assert_eq!(current(&throttle, VIMEO), 2);
+
+        for _ in 0..3 {
+            advance(THROTTLE_DEBOUNCE).await;
+            throttle.throttled(VIMEO);
+        }
+        assert_eq!(current(&throttle, VIMEO), 1);
+
+        let fixed = Throttle::new(8, &[], false);
+        fixed.throttled(VIMEO);
+        assert_eq!(current(&fixed, VIMEO), 8);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ramps_concurrency_back_up() {
+        let throttle = Throttle::new(4, &[], true);
+        throttle.throttled(VIMEO);
+        assert_eq!(current(&throttle, VIMEO), 2);
+
+        throttle.succeeded(VIMEO);
+        assert_eq!(current(&throttle, VIMEO), 2);
+
+        advance(RAMP_UP_INTERVAL).await;
+        throttle.succeeded(VIMEO);
+        throttle.succeeded(VIMEO);
+        assert_eq!(current(&throttle, VIMEO), 3);
+
+        advance(RAMP_UP_INTERVAL).await;
+        throttle.succeeded(VIMEO);
+        advance(RAMP_UP_INTERVAL).await;
+        throttle.succeeded(VIMEO);
+        assert_eq!(current(&throttle, VIMEO), 4);
+    }
+}
@@ -0,0 +1,107 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    process::Stdio,
+    time::Duration,
+};
+
+use color_eyre::{eyre::WrapErr, Result};
+use tokio::{process::Command, time::sleep};
+use tracing::{error, info, instrument, warn, Level};
+
+use crate::{
+    args::WatchArgs, changes::CHANGES_FILE_NAME, shutdown::Shutdown, sync_course, SyncOutcome,
+};
+
+/// Re-sync the course on an interval, until a shutdown signal is received.
+///
+/// Syncs use mirror mode, so that only new or changed content is downloaded.
+#[instrument(level = Level::DEBUG, skip(args, shutdown))]
+pub(crate) async fn run(args: WatchArgs, shutdown: &Shutdown) -> Result<()> {
+    let mut download_args = args.download;
+    download_args.mirror = true;
+
+    loop {
+        info!("Syncing course...");
+
+        match sync_course(&download_args, shutdown).await {
+            Ok(outcome) => notify(&outcome, args.on_change.as_deref()).await,
+            // Keep watching - the next sync might succeed, e.g. after a network outage.
+            Err(report) => error!("Sync failed: {report:?}"),
+        }
+
+        if shutdown.is_stopping() {
+            info!("Stopping.");
+            return Ok(());
+        }
+
+        let delay = args.interval + random_jitter(args.jitter);
+        info!("Next sync in {}.", humantime::format_duration(delay));
+
+        tokio::select! {
+            _ = sleep(delay) => {}
+            _ = shutdown.stopping() => {
+                info!("Stopping.");
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Emit an event if new or changed content arrived, running the `--on-change` command, if any.
+async fn notify(outcome: &SyncOutcome, on_change: Option<&str>) {
+    let Some(changes) = outcome
+        .changes
+        .as_ref()
+        .filter(|changes| !changes.is_empty())
+    else {
+        return;
+    };
+
+    info!(
+        "New content arrived: {} new and {} changed asset(s), {} new item(s).",
+        changes.new_assets.len(),
+        changes.changed_assets.len(),
+        changes.new_items.len()
+    );
+
+    if let Some(on_change) = on_change {
+        #[cfg(windows)]
+        let mut command = {
+            let mut command = Command::new("cmd");
+            command.arg("/C");
+            command
+        };
+        #[cfg(not(windows))]
+        let mut command = {
+            let mut command = Command::new("sh");
+            command.arg("-c");
+            command
+        };
+
+        let status = command
+            .arg(on_change)
+            .env("ELOPAGE_COURSE_DIR", &outcome.course_path)
+            .env(
+                "ELOPAGE_CHANGES_FILE",
+                outcome.course_path.join(CHANGES_FILE_NAME),
+            )
+            .stdin(Stdio::null())
+            .status()
+            .await
+            .wrap_err("Failed to run `--on-change` command");
+
+        match status {
+            Ok(status) if status.success() => {}
+            Ok(status) => warn!("`--on-change` command exited with {status}."),
+            Err(report) => error!("{report:?}"),
+        }
+    }
+}
+
+/// A random duration between zero and `max`.
+fn random_jitter(max: Duration) -> Duration {
+    // `RandomState` is randomly seeded, which is plenty for spreading out sync times.
+    let random = RandomState::new().build_hasher().finish();
+    max.mul_f64(random as f64 / u64::MAX as f64)
+}